@@ -58,6 +58,23 @@
 
         #Always show Wayland sessions
         sed -i 's/dri_active = .*/dri_active = true;/' src/greeter/SessionModel.cpp
+
+        #Support the secret prompt / device status protocol extensions of the LUKS unlock daemon (see sddm-daemon/src/greeter_protocol.rs)
+        # - advertise support for them after connecting
+        # - show secret prompts (daemon message 5) as information messages, and answer them (greeter message 7) with the next entered password
+        # - show missing devices (daemon message 6 with status 5) as information messages, and skip them (greeter message 9) when logging in with an empty password
+        sed -i '/namespace SDDM {/a static bool secretPromptPending = false;\
+static QString missingDevice;' src/greeter/GreeterProxy.cpp
+        sed -i '/<< quint32(GreeterMessages::Connect)/a SocketWriter(d->socket) << quint32(8) << quint32(0b11);' src/greeter/GreeterProxy.cpp
+        sed -i '/input >> message;/a if (message == quint32(DaemonMessages::LoginSucceeded) || message == quint32(DaemonMessages::LoginFailed)) secretPromptPending = false;\
+if (message == 5) { quint32 kind; QString prompt; input >> kind >> prompt; secretPromptPending = true; emit informationMessage(prompt); continue; }\
+if (message == 6) { quint32 index, count, status; QString device; input >> index >> count >> status >> device; if (status == 5) { missingDevice = device; emit informationMessage(device + QStringLiteral(" is not available; log in with an empty password to continue without it")); } else if (device == missingDevice) { missingDevice.clear(); } continue; }' src/greeter/GreeterProxy.cpp
+        sed -i '/<< quint32(GreeterMessages::Login)/i if (secretPromptPending) { secretPromptPending = false; SocketWriter(d->socket) << quint32(7) << password; return; }\
+if (password.isEmpty() && !missingDevice.isEmpty()) { SocketWriter(d->socket) << quint32(9) << missingDevice; missingDevice.clear(); return; }' src/greeter/GreeterProxy.cpp
+
+        for marker in "quint32(8)" "secretPromptPending = true" "quint32(7)" "missingDevice = device" "quint32(9)"; do
+          grep -qF "$marker" src/greeter/GreeterProxy.cpp || { echo "failed to patch in LUKS unlock protocol extensions"; exit 1; }
+        done
       '';
     }
   )
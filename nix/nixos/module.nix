@@ -55,6 +55,7 @@
   defaultConfig =
     {
       LUKSUnlock.Greeter = lib.getExe' cfg.packages.sddm-minimal "sddm-greeter-qt6";
+      LUKSUnlock.Users = cfg.users;
       LUKSUnlock.Devices = map (name: config.boot.initrd.luks.devices.${name}.device) cfg.luksDevices;
     }
     // (lib.optionalAttrs (cfg.theme.name != "") {
@@ -69,6 +70,16 @@
         } ''
           cp -rL ${dmCfg.sessionData.desktops} $out
           chmod -R u+w $out
+
+          #Add a session which only unlocks the LUKS devices, and leaves logging in to the stage 2 greeter
+          mkdir -p $out/share/wayland-sessions
+          cat > $out/share/wayland-sessions/luks-unlock-only.desktop <<EOF
+          [Desktop Entry]
+          Type=Application
+          Name=Unlock only
+          Comment=Unlock the disks, then log in normally
+          Exec=/proc/self/exe
+          EOF
           find $out -type f -exec sed -i "/^Exec=/d" {} \;
           find $out -type f -exec sed -i "s|Exec=.*|Exec=/proc/self/exe|" {} \;
         '';
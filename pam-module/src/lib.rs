@@ -1,15 +1,20 @@
-use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
 
 use nonstick::{ConversationAdapter, ModuleClient, PamModule, items::ItemsMut, pam_export};
 use zeroize::Zeroizing;
 
+#[path = "../../sddm-daemon/src/file_flags.rs"]
+mod file_flags;
+
+use file_flags::{FS_IMMUTABLE_FL, get_file_flags, set_file_flags};
+
 struct SddmInitrdAutologin;
 pam_export!(SddmInitrdAutologin);
 
 impl<M: ModuleClient> PamModule<M> for SddmInitrdAutologin {
     fn authenticate(
         handle: &mut M,
-        _args: Vec<&std::ffi::CStr>,
+        args: Vec<&std::ffi::CStr>,
         _flags: nonstick::AuthnFlags,
     ) -> nonstick::Result<()> {
         //Read the transient SDDM config file written by the daemon
@@ -98,6 +103,28 @@ impl<M: ModuleClient> PamModule<M> for SddmInitrdAutologin {
             "handing off initrd LUKS unlock login request for user {user:?}"
         );
 
+        //Report and clear the persistent failed unlock counter maintained by the daemon
+        let mut efivarfs = "/sys/firmware/efi/efivars";
+        for &arg in &args {
+            let arg = arg.to_str().map_err(|_| nonstick::ErrorCode::BufferError)?;
+            if let Some(root) = arg.strip_prefix("efivarfs=") {
+                efivarfs = root;
+            }
+        }
+
+        // - conversation messages aren't shown during autologins; the initrd greeter already showed the count to the user
+        match take_failed_unlocks(Path::new(efivarfs)) {
+            Ok(0) => {}
+            Ok(count) => nonstick::info!(
+                handle,
+                "clearing initrd LUKS unlock failed unlock counter for user {user:?} ({count} failed attempts)"
+            ),
+            Err(err) => nonstick::error!(
+                handle,
+                "failed to clear initrd LUKS unlock failed unlock counter: {err:#}"
+            ),
+        }
+
         Ok(())
     }
 
@@ -187,3 +214,85 @@ impl<M: ModuleClient> PamModule<M> for SddmInitrdAutologin {
         Ok(())
     }
 }
+
+//The daemon counts failed unlock attempts in a vendor EFI variable; this has to be kept in sync with it
+const FAILED_UNLOCKS_VAR: &str = "LUKSStage1SddmFailedUnlocks-c508b668-ea00-45bb-96b5-e0fb6908709e";
+
+fn take_failed_unlocks(efivarfs: &Path) -> std::io::Result<u32> {
+    let path = efivarfs.join(FAILED_UNLOCKS_VAR);
+
+    // - efivarfs files consist of the variable's attributes, followed by its data
+    let count = match std::fs::read(&path) {
+        Ok(var) => var
+            .get(4..8)
+            .map_or(0, |d| u32::from_le_bytes(d.try_into().unwrap())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    // - efivarfs marks variables of unknown vendors immutable, which has to be lifted before they can be removed
+    clear_immutable_flag(&path)?;
+    std::fs::remove_file(path)?;
+    Ok(count)
+}
+
+fn clear_immutable_flag(path: &Path) -> std::io::Result<()> {
+    let file = std::fs::File::open(path)?;
+
+    // - not every filesystem supports file flags
+    if let Some(flags) = get_file_flags(&file)?
+        && flags & FS_IMMUTABLE_FL != 0
+    {
+        set_file_flags(&file, flags & !FS_IMMUTABLE_FL)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_var(efivarfs: &Path, count: u32) -> std::path::PathBuf {
+        let path = efivarfs.join(FAILED_UNLOCKS_VAR);
+        let mut var = 0x7u32.to_le_bytes().to_vec();
+        var.extend_from_slice(&count.to_le_bytes());
+        std::fs::write(&path, var).unwrap();
+        path
+    }
+
+    #[test]
+    fn missing_variable_is_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(take_failed_unlocks(dir.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn take_reads_and_clears_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_var(dir.path(), 3);
+
+        assert_eq!(take_failed_unlocks(dir.path()).unwrap(), 3);
+        assert!(!path.exists());
+        assert_eq!(take_failed_unlocks(dir.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn take_clears_immutable_variables() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_var(dir.path(), 5);
+
+        // - setting the immutable flag requires CAP_LINUX_IMMUTABLE and filesystem support
+        let file = std::fs::File::open(&path).unwrap();
+        let Ok(Some(flags)) = get_file_flags(&file) else {
+            return;
+        };
+        if set_file_flags(&file, flags | FS_IMMUTABLE_FL).is_err() {
+            return;
+        }
+        assert!(std::fs::remove_file(&path).is_err());
+
+        assert_eq!(take_failed_unlocks(dir.path()).unwrap(), 5);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,32 @@
+#![no_main]
+
+#[path = "../../src/greeter_protocol.rs"]
+#[allow(dead_code)]
+mod greeter_protocol;
+
+use greeter_protocol::{DaemonMessage, GreeterMessage};
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    smol::block_on(async {
+        //Decode as many messages as possible; anything we decode has to survive an encode / decode round trip
+        let mut stream = data;
+        while let Ok(Some(msg)) = GreeterMessage::read_from(&mut stream).await {
+            let mut buf = Vec::new();
+            msg.encode(&mut buf);
+            assert_eq!(
+                GreeterMessage::read_from(&mut &buf[..]).await.unwrap(),
+                Some(msg)
+            );
+        }
+
+        let mut stream = data;
+        while let Ok(Some(msg)) = DaemonMessage::read_from(&mut stream).await {
+            let mut buf = Vec::new();
+            msg.encode(&mut buf);
+            assert_eq!(
+                DaemonMessage::read_from(&mut &buf[..]).await.unwrap(),
+                Some(msg)
+            );
+        }
+    });
+});
@@ -0,0 +1,440 @@
+//! Argon2 ([RFC 9106](https://www.rfc-editor.org/rfc/rfc9106)) which computes its lanes in parallel, like cryptsetup does
+
+use anyhow::{Result, ensure};
+use blake2::{
+    Blake2b512, Blake2bVar,
+    digest::{Digest, Update, VariableOutput},
+};
+use zeroize::Zeroizing;
+
+//The argon2 crate computes lanes one after another, which would make checking a passphrase take `cpus` times as long as unlocking the device
+// - lanes only reference each other's segments across sync points, so the segments of each slice can be filled concurrently
+
+const SYNC_POINTS: usize = 4;
+const BLOCK_WORDS: usize = 128; // - 1 KiB blocks
+const VERSION: u32 = 0x13;
+
+type Block = [u64; BLOCK_WORDS];
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Argon2i = 1,
+    Argon2id = 2,
+}
+
+struct Params {
+    variant: Variant,
+    time: usize,
+    lanes: usize,
+    lane_length: usize,
+    segment_length: usize,
+}
+
+pub fn argon2(
+    variant: Variant,
+    time: u32,
+    memory: u32, // - in KiB
+    lanes: u32,
+    password: &[u8],
+    salt: &[u8],
+    out: &mut [u8],
+) -> Result<()> {
+    ensure!(time >= 1, "invalid Argon2 time cost {time}");
+    ensure!(
+        (1..=0xFFFFFF).contains(&lanes),
+        "invalid Argon2 lane count {lanes}"
+    );
+    ensure!(
+        memory >= 8 * lanes,
+        "invalid Argon2 memory cost {memory} for {lanes} lanes"
+    );
+    ensure!(salt.len() >= 8, "Argon2 salt is too short");
+    ensure!(out.len() >= 4, "Argon2 output is too short");
+
+    // - the memory is rounded down to a multiple of the number of blocks per slice
+    let segment_length = memory as usize / (lanes as usize * SYNC_POINTS);
+    let params = Params {
+        variant,
+        time: time as usize,
+        lanes: lanes as usize,
+        lane_length: segment_length * SYNC_POINTS,
+        segment_length,
+    };
+
+    //Hash the inputs into the initial blocks of each lane
+    let mut h0 = Blake2b512::new();
+    for val in [
+        lanes,
+        out.len() as u32,
+        memory,
+        time,
+        VERSION,
+        variant as u32,
+    ] {
+        Digest::update(&mut h0, val.to_le_bytes());
+    }
+    // - we don't use a secret / associated data
+    for input in [password, salt, &[], &[]] {
+        Digest::update(&mut h0, (input.len() as u32).to_le_bytes());
+        Digest::update(&mut h0, input);
+    }
+    let h0 = Zeroizing::new(<[u8; 64]>::from(h0.finalize()));
+
+    let mut memory = Zeroizing::new(vec![[0u64; BLOCK_WORDS]; params.lane_length * params.lanes]);
+    let mut bytes = Zeroizing::new([0u8; BLOCK_WORDS * 8]);
+    for (l, lane) in memory.chunks_exact_mut(params.lane_length).enumerate() {
+        for (i, block) in lane[..2].iter_mut().enumerate() {
+            blake2b_long(
+                &[
+                    &h0[..],
+                    &(i as u32).to_le_bytes(),
+                    &(l as u32).to_le_bytes(),
+                ],
+                &mut bytes[..],
+            );
+            load_block(block, &bytes);
+        }
+    }
+
+    //Fill the memory one slice at a time, with each thread filling the segments of some of the lanes
+    let threads = std::thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(params.lanes);
+
+    for pass in 0..params.time {
+        for slice in 0..SYNC_POINTS {
+            // - split each lane into the segment being filled and the rest of the lane, which may be referenced by any lane
+            let seg_start = slice * params.segment_length;
+            let mut segments = Vec::with_capacity(params.lanes);
+            let mut rest = Vec::with_capacity(params.lanes);
+            for lane in memory.chunks_exact_mut(params.lane_length) {
+                let (before, lane) = lane.split_at_mut(seg_start);
+                let (segment, after) = lane.split_at_mut(params.segment_length);
+                segments.push(segment);
+                rest.push((&*before, &*after));
+            }
+
+            let mut work: Vec<Vec<_>> = (0..threads).map(|_| Vec::new()).collect();
+            for (lane, segment) in segments.into_iter().enumerate() {
+                work[lane % threads].push((lane, segment));
+            }
+
+            let (params, rest) = (&params, &rest[..]);
+            std::thread::scope(|s| {
+                for segments in work {
+                    s.spawn(move || {
+                        for (lane, segment) in segments {
+                            fill_segment(params, rest, pass, slice, lane, segment);
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    //Hash the XOR of the last blocks of each lane into the output
+    let mut last = Zeroizing::new([0u64; BLOCK_WORDS]);
+    for lane in memory.chunks_exact(params.lane_length) {
+        xor_block(&mut last, &lane[params.lane_length - 1]);
+    }
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(last.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    blake2b_long(&[&bytes[..]], out);
+
+    Ok(())
+}
+
+fn fill_segment(
+    params: &Params,
+    rest: &[(&[Block], &[Block])],
+    pass: usize,
+    slice: usize,
+    lane: usize,
+    segment: &mut [Block],
+) {
+    let seg_start = slice * params.segment_length;
+
+    // - Argon2id only uses data-independent addressing for the first half of the first pass
+    let data_independent =
+        params.variant == Variant::Argon2i || (pass == 0 && slice < SYNC_POINTS / 2);
+
+    let mut address = Zeroizing::new([0u64; BLOCK_WORDS]);
+    let mut input = Zeroizing::new([0u64; BLOCK_WORDS]);
+    if data_independent {
+        input[..6].copy_from_slice(&[
+            pass as u64,
+            lane as u64,
+            slice as u64,
+            (params.lane_length * params.lanes) as u64,
+            params.time as u64,
+            params.variant as u64,
+        ]);
+    }
+
+    // - the first two blocks of each lane were already initialized
+    let first = if pass == 0 && slice == 0 {
+        if data_independent {
+            next_addresses(&mut address, &mut input);
+        }
+        2
+    } else {
+        0
+    };
+
+    for idx in first..params.segment_length {
+        let prev = if idx > 0 {
+            &segment[idx - 1]
+        } else {
+            // - the first block of a segment follows the last block of the previous segment, wrapping around to the end of the lane
+            let prev_idx = (seg_start + params.lane_length - 1) % params.lane_length;
+            lane_block(rest, segment, lane, seg_start, lane, prev_idx)
+        };
+
+        //Determine the reference block
+        let rand = if data_independent {
+            if idx % BLOCK_WORDS == 0 {
+                next_addresses(&mut address, &mut input);
+            }
+            address[idx % BLOCK_WORDS]
+        } else {
+            prev[0]
+        };
+
+        // - the first slice of the first pass can't reference other lanes yet
+        let ref_lane = if pass == 0 && slice == 0 {
+            lane
+        } else {
+            (rand >> 32) as usize % params.lanes
+        };
+
+        // - blocks may reference all finished segments, and the blocks of their own segment before the previous block
+        let finished = if pass == 0 {
+            slice * params.segment_length
+        } else {
+            params.lane_length - params.segment_length
+        };
+        let area_size = if ref_lane == lane {
+            finished + idx - 1
+        } else if idx == 0 {
+            finished - 1
+        } else {
+            finished
+        };
+
+        let map = ((rand & 0xFFFFFFFF) * (rand & 0xFFFFFFFF)) >> 32;
+        let relative_pos = area_size - 1 - ((area_size as u64 * map) >> 32) as usize;
+
+        let start_pos = if pass != 0 && slice != SYNC_POINTS - 1 {
+            seg_start + params.segment_length
+        } else {
+            0
+        };
+        let ref_idx = (start_pos + relative_pos) % params.lane_length;
+        let reference = lane_block(rest, segment, lane, seg_start, ref_lane, ref_idx);
+
+        //Compute the new block; later passes XOR it into the existing one
+        let block = compress(prev, reference);
+        if pass == 0 {
+            segment[idx] = block;
+        } else {
+            xor_block(&mut segment[idx], &block);
+        }
+    }
+}
+
+//Looks up a block of any lane while the segments of the current slice are being filled
+fn lane_block<'a>(
+    rest: &'a [(&[Block], &[Block])],
+    segment: &'a [Block],
+    own_lane: usize,
+    seg_start: usize,
+    lane: usize,
+    idx: usize,
+) -> &'a Block {
+    let (before, after) = rest[lane];
+    if idx < seg_start {
+        &before[idx]
+    } else if idx < seg_start + segment.len() {
+        // - the segments of other lanes are never referenced while they're being filled
+        assert_eq!(lane, own_lane, "referenced segment of another lane");
+        &segment[idx - seg_start]
+    } else {
+        &after[idx - seg_start - segment.len()]
+    }
+}
+
+fn next_addresses(address: &mut Block, input: &mut Block) {
+    const ZERO: Block = [0u64; BLOCK_WORDS];
+
+    input[6] += 1;
+    *address = compress(&ZERO, input);
+    *address = compress(&ZERO, address);
+}
+
+fn compress(x: &Block, y: &Block) -> Block {
+    // - let the compiler vectorize the permutations if possible
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("avx2") {
+        #[target_feature(enable = "avx2")]
+        fn compress_avx2(x: &Block, y: &Block) -> Block {
+            compress_generic(x, y)
+        }
+
+        return unsafe { compress_avx2(x, y) };
+    }
+
+    compress_generic(x, y)
+}
+
+#[inline(always)]
+fn compress_generic(x: &Block, y: &Block) -> Block {
+    let mut r = *x;
+    xor_block(&mut r, y);
+
+    //Permute each row of 16 words, then each column of 2x8 words
+    let mut q = r;
+    for row in 0..8 {
+        permute(&mut q, std::array::from_fn(|i| 16 * row + i));
+    }
+    for col in 0..8 {
+        permute(
+            &mut q,
+            std::array::from_fn(|i| 2 * col + 16 * (i / 2) + i % 2),
+        );
+    }
+
+    xor_block(&mut q, &r);
+    q
+}
+
+#[inline(always)]
+fn permute(v: &mut Block, idx: [usize; 16]) {
+    mix(v, idx[0], idx[4], idx[8], idx[12]);
+    mix(v, idx[1], idx[5], idx[9], idx[13]);
+    mix(v, idx[2], idx[6], idx[10], idx[14]);
+    mix(v, idx[3], idx[7], idx[11], idx[15]);
+    mix(v, idx[0], idx[5], idx[10], idx[15]);
+    mix(v, idx[1], idx[6], idx[11], idx[12]);
+    mix(v, idx[2], idx[7], idx[8], idx[13]);
+    mix(v, idx[3], idx[4], idx[9], idx[14]);
+}
+
+//The BLAKE2b G function, with the additions replaced by BlaMka's multiplication-hardened ones
+#[inline(always)]
+fn mix(v: &mut Block, a: usize, b: usize, c: usize, d: usize) {
+    let blamka = |x: u64, y: u64| {
+        x.wrapping_add(y)
+            .wrapping_add(2u64.wrapping_mul((x & 0xFFFFFFFF) * (y & 0xFFFFFFFF)))
+    };
+
+    v[a] = blamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = blamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = blamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = blamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+fn xor_block(dst: &mut Block, src: &Block) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s);
+}
+
+fn load_block(block: &mut Block, bytes: &[u8; BLOCK_WORDS * 8]) {
+    for (word, chunk) in block.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+}
+
+//The variable-length hash function H' of Argon2
+fn blake2b_long(inputs: &[&[u8]], out: &mut [u8]) {
+    let len = (out.len() as u32).to_le_bytes();
+
+    if out.len() <= 64 {
+        let mut hasher = Blake2bVar::new(out.len()).unwrap();
+        Update::update(&mut hasher, &len);
+        inputs.iter().for_each(|i| Update::update(&mut hasher, i));
+        hasher.finalize_variable(out).unwrap();
+        return;
+    }
+
+    // - longer outputs are built from the first halves of a chain of hashes, followed by a final hash of the remaining length
+    let mut hasher = Blake2b512::new();
+    Digest::update(&mut hasher, len);
+    inputs.iter().for_each(|i| Digest::update(&mut hasher, i));
+    let mut hash = Zeroizing::new(<[u8; 64]>::from(hasher.finalize()));
+
+    out[..32].copy_from_slice(&hash[..32]);
+    let mut pos = 32;
+    while out.len() - pos > 64 {
+        *hash = Blake2b512::digest(&hash[..]).into();
+        out[pos..pos + 32].copy_from_slice(&hash[..32]);
+        pos += 32;
+    }
+
+    let mut hasher = Blake2bVar::new(out.len() - pos).unwrap();
+    Update::update(&mut hasher, &hash[..]);
+    hasher.finalize_variable(&mut out[pos..]).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Cross-check against the (sequential) argon2 crate
+    fn reference(variant: Variant, time: u32, memory: u32, lanes: u32, out_len: usize) -> Vec<u8> {
+        let algorithm = match variant {
+            Variant::Argon2i => ::argon2::Algorithm::Argon2i,
+            Variant::Argon2id => ::argon2::Algorithm::Argon2id,
+        };
+        let params = ::argon2::Params::new(memory, time, lanes, Some(out_len)).unwrap();
+
+        let mut out = vec![0u8; out_len];
+        ::argon2::Argon2::new(algorithm, ::argon2::Version::V0x13, params)
+            .hash_password_into(b"correct horse", b"battery staple", &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn matches_reference() {
+        for variant in [Variant::Argon2i, Variant::Argon2id] {
+            for (time, memory, lanes, out_len) in [
+                (1, 8, 1, 32),
+                (3, 64, 1, 64),
+                (2, 37, 3, 32),  // - the memory isn't a multiple of the lanes' slices
+                (2, 256, 4, 80), // - outputs longer than a single BLAKE2b hash
+                (1, 2048, 8, 512),
+            ] {
+                let mut out = vec![0u8; out_len];
+                argon2(
+                    variant,
+                    time,
+                    memory,
+                    lanes,
+                    b"correct horse",
+                    b"battery staple",
+                    &mut out,
+                )
+                .unwrap();
+
+                assert_eq!(
+                    out,
+                    reference(variant, time, memory, lanes, out_len),
+                    "{variant:?} t={time} m={memory} p={lanes}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        let mut out = [0u8; 32];
+        assert!(argon2(Variant::Argon2id, 0, 64, 1, b"", b"saltsalt", &mut out).is_err());
+        assert!(argon2(Variant::Argon2id, 1, 31, 4, b"", b"saltsalt", &mut out).is_err());
+        assert!(argon2(Variant::Argon2id, 1, 64, 1, b"", b"salt", &mut out).is_err());
+    }
+}
@@ -1,21 +1,31 @@
 use std::{
-    io::ErrorKind,
-    mem::MaybeUninit,
     ops::DerefMut,
-    path::{Path, PathBuf},
-    str,
-    sync::Arc,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicI32, AtomicU32, Ordering},
+    },
+    time::Duration,
 };
 
-use anyhow::{Result, bail, ensure};
+use anyhow::{Context, Result, bail, ensure};
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::{Pid, getuid},
+};
 use smol::{
     future::FutureExt,
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::AsyncWrite,
     net::unix::{UnixListener, UnixStream},
 };
 use zeroize::Zeroizing;
 
-use crate::power_actions::PowerAction;
+use crate::{
+    greeter_protocol::{
+        DaemonMessage, DeviceStatus, Extension, GreeterMessage, SecretKind, SessionType,
+    },
+    power_actions::PowerAction,
+};
 
 pub trait GreeterController: Send + Sync + 'static {
     fn login(
@@ -23,27 +33,248 @@ pub trait GreeterController: Send + Sync + 'static {
         user: &str,
         password: Zeroizing<Box<str>>,
         session: &Path,
-        msg_sender: impl FnMut(&str) + Send + Sync,
-    ) -> impl Future<Output = bool> + Send;
+        session_type: SessionType,
+        greeter: &GreeterPrompter,
+    ) -> impl Future<Output = Result<(), LoginError>> + Send;
+
+    fn skip_device(&self, device: &str) -> impl Future<Output = ()> + Send;
+
+    fn subscribe_notifications(&self) -> smol::channel::Receiver<GreeterNotification>;
 
     fn can_perform_power_action(&self, act: PowerAction) -> bool;
     fn perform_power_action(&self, act: PowerAction) -> impl Future<Output = ()> + Send;
 }
 
-pub async fn greeter_control_server(socket_path: PathBuf, controller: Arc<impl GreeterController>) {
-    //Bind the socket and accept any connections from greeters
-    let socket = UnixListener::bind(&socket_path).expect("failed to bind greeter control socket");
+#[derive(Debug, Clone)]
+pub enum GreeterNotification {
+    Information(String),
+    DeviceStatus {
+        index: usize,
+        count: usize,
+        device: String,
+        status: DeviceStatus,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginError {
+    WrongPassword {
+        device: String,
+        remaining_attempts: Option<u32>,
+    },
+    NoUserKeyslot {
+        user: String,
+        device: String,
+    },
+    DeviceMissing {
+        device: String,
+    },
+    CryptsetupAborted {
+        device: String,
+    },
+    TimedOut {
+        device: String,
+    },
+    UserNotAllowed {
+        user: String,
+    },
+    SecretPromptUnavailable {
+        device: String,
+    },
+    KeyslotBindingUnchecked {
+        device: String,
+    },
+    InvalidSession {
+        session: String,
+    },
+    ShuttingDown,
+}
+
+impl LoginError {
+    //Whether the error indicates that the user entered wrong credentials, and should count towards any brute-force limits
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self,
+            LoginError::WrongPassword { .. }
+                | LoginError::NoUserKeyslot { .. }
+                | LoginError::UserNotAllowed { .. }
+        )
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            LoginError::WrongPassword { .. } => 10,
+            LoginError::NoUserKeyslot { .. } => 11,
+            LoginError::DeviceMissing { .. } => 12,
+            LoginError::CryptsetupAborted { .. } => 13,
+            LoginError::TimedOut { .. } => 14,
+            LoginError::UserNotAllowed { .. } => 15,
+            LoginError::ShuttingDown => 16,
+            LoginError::SecretPromptUnavailable { .. } => 17,
+            LoginError::KeyslotBindingUnchecked { .. } => 18,
+            LoginError::InvalidSession { .. } => 19,
+        }
+    }
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::WrongPassword {
+                device,
+                remaining_attempts,
+            } => match remaining_attempts {
+                Some(1) => write!(f, "wrong password for {device} (1 attempt remaining)"),
+                Some(n) => write!(f, "wrong password for {device} ({n} attempts remaining)"),
+                None => write!(f, "wrong password for {device}"),
+            },
+            LoginError::NoUserKeyslot { user, device } => {
+                write!(f, "user {user} has no keyslot on {device}")
+            }
+            LoginError::DeviceMissing { device } => write!(f, "{device} is not available"),
+            LoginError::CryptsetupAborted { device } => {
+                write!(f, "cryptsetup gave up on unlocking {device}")
+            }
+            LoginError::TimedOut { device } => {
+                write!(f, "the password request for {device} timed out")
+            }
+            LoginError::UserNotAllowed { user } => {
+                write!(f, "user {user} is not allowed to log in")
+            }
+            LoginError::SecretPromptUnavailable { device } => {
+                write!(f, "couldn't prompt for the PIN / passphrase of {device}")
+            }
+            LoginError::KeyslotBindingUnchecked { device } => {
+                write!(f, "couldn't check which users may unlock {device}")
+            }
+            LoginError::InvalidSession { session } => {
+                write!(f, "session {session} does not exist")
+            }
+            LoginError::ShuttingDown => write!(f, "the system is already starting up"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+//How long the user has to answer secret prompts before they're abandoned
+const SECRET_PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+//Allows the controller to interact with the greeter which issued a login request
+pub struct GreeterPrompter {
+    stream: Arc<smol::lock::Mutex<UnixStream>>,
+    extensions: Arc<AtomicU32>,
+    secret_rx: smol::channel::Receiver<Zeroizing<Box<str>>>,
+    error: std::sync::Mutex<Option<std::io::Error>>,
+}
+
+pub enum SecretPromptResult {
+    Answered(Zeroizing<Box<str>>),
+    Cancelled,
+    Unavailable, // - the greeter can't prompt for secrets, or the user didn't answer in time
+    Failed,      // - talking to the greeter failed, e.g. because it disconnected
+}
+
+impl GreeterPrompter {
+    pub async fn send_message(&self, msg: &str) {
+        let res = send_notification(
+            &self.stream,
+            GreeterNotification::Information(msg.to_string()),
+        )
+        .await;
+
+        self.record_result(res);
+    }
+
+    pub async fn prompt_secret(&self, kind: SecretKind, prompt: &str) -> SecretPromptResult {
+        // - stock greeters don't understand secret prompts
+        if self.extensions.load(Ordering::Acquire) & Extension::SecretPrompts as u32 == 0 {
+            println!("greeter doesn't support secret prompts; not prompting for {prompt:?}");
+            return SecretPromptResult::Unavailable;
+        }
+
+        //Discard any stale responses to earlier prompts
+        while self.secret_rx.try_recv().is_ok() {}
 
-    let mut conns = Vec::new();
+        let res = DaemonMessage::SecretPrompt {
+            kind,
+            prompt: prompt.into(),
+        }
+        .write_to(self.stream.lock().await.deref_mut())
+        .await;
+
+        if !self.record_result(res) {
+            return SecretPromptResult::Failed;
+        }
+
+        // - an empty response means that the user cancelled the prompt
+        smol::future::or(
+            async {
+                match self.secret_rx.recv().await {
+                    Ok(secret) if !secret.is_empty() => SecretPromptResult::Answered(secret),
+                    Ok(_) => SecretPromptResult::Cancelled,
+                    Err(_) => SecretPromptResult::Failed,
+                }
+            },
+            async {
+                smol::Timer::after(SECRET_PROMPT_TIMEOUT).await;
+                println!("secret prompt {prompt:?} timed out");
+                SecretPromptResult::Unavailable
+            },
+        )
+        .await
+    }
+
+    fn record_result(&self, res: std::io::Result<()>) -> bool {
+        match res {
+            Ok(()) => true,
+            Err(err) => {
+                self.error.lock().unwrap().get_or_insert(err);
+                false
+            }
+        }
+    }
+}
+
+pub async fn greeter_control_server(
+    socket: UnixListener,
+    greeter_pid: Arc<AtomicI32>,
+    controller: Arc<impl GreeterController>,
+) {
+    //Accept connections from the greeter, one at a time
+    let mut conn_task: Option<smol::Task<()>> = None;
+    let mut conn_id = 0;
     loop {
         let (conn, _) = socket
             .accept()
             .await
             .expect("failed to accept greeter control socket connection");
 
-        let conn_id = conns.len();
+        // - only the greeter we spawned may control us
+        // - a restarted greeter might connect before the supervisor published its PID, so give it a moment to catch up
+        let mut peer_check = check_peer_credentials(&conn, &greeter_pid);
+        for _ in 0..PEER_CHECK_RETRIES {
+            if peer_check.is_ok() {
+                break;
+            }
+
+            smol::Timer::after(PEER_CHECK_RETRY_DELAY).await;
+            peer_check = check_peer_credentials(&conn, &greeter_pid);
+        }
+
+        if let Err(err) = peer_check {
+            eprintln!("rejecting greeter control socket connection: {err:#}");
+            continue;
+        }
+
+        if conn_task.as_ref().is_some_and(|t| !t.is_finished()) {
+            eprintln!("rejecting concurrent greeter control socket connection");
+            continue;
+        }
+
+        conn_id += 1;
         let controller = controller.clone();
-        conns.push(smol::spawn(async move {
+        conn_task = Some(smol::spawn(async move {
             println!("accepted greeter control socket connection {conn_id}");
             if let Err(err) = greeter_control_connection(conn, controller).await {
                 eprintln!("failed to handle greeter connection {conn_id}: {err:#}");
@@ -54,18 +285,34 @@ pub async fn greeter_control_server(socket_path: PathBuf, controller: Arc<impl G
     }
 }
 
+const PEER_CHECK_RETRIES: usize = 10;
+const PEER_CHECK_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn check_peer_credentials(conn: &UnixStream, greeter_pid: &AtomicI32) -> Result<()> {
+    let creds = getsockopt(conn, PeerCredentials).context("failed to query peer credentials")?;
+    let greeter_pid = Pid::from_raw(greeter_pid.load(Ordering::Acquire));
+
+    ensure!(
+        creds.pid() == greeter_pid.as_raw(),
+        "peer PID {} is not the greeter (PID {greeter_pid})",
+        creds.pid()
+    );
+    ensure!(
+        creds.uid() == getuid().as_raw(),
+        "peer UID {} does not match our UID",
+        creds.uid()
+    );
+
+    Ok(())
+}
+
 async fn greeter_control_connection(
     mut conn: UnixStream,
     controller: Arc<impl GreeterController>,
 ) -> Result<()> {
     //Perform the initial handshake
-    match recv_msg(&mut conn).await? {
-        Some(msg) => {
-            ensure!(
-                msg == GreeterMessage::Connect as u32,
-                "unexpected first message: {msg}"
-            );
-
+    match GreeterMessage::read_from(&mut conn).await? {
+        Some(GreeterMessage::Connect) => {
             let mut caps = 0;
             for act in PowerAction::ALL_ACTIONS {
                 if controller.can_perform_power_action(act) {
@@ -74,39 +321,63 @@ async fn greeter_control_connection(
             }
 
             //Send the controller's capabilities / hostname
-            conn.write_all(&u32::to_be_bytes(DaemonMessage::Capabilities as u32))
+            DaemonMessage::Capabilities(caps)
+                .write_to(&mut conn)
                 .await?;
-            conn.write_all(&u32::to_be_bytes(caps)).await?;
 
             if let Some(hostname) = gethostname::gethostname().to_str() {
-                conn.write_all(&u32::to_be_bytes(DaemonMessage::HostName as u32))
+                DaemonMessage::HostName(hostname.into())
+                    .write_to(&mut conn)
                     .await?;
-                send_string(&mut conn, hostname).await?;
             }
         }
+        Some(msg) => bail!("unexpected first message: {msg:?}"),
         None => return Ok(()),
     }
 
     //Handle messages received from the connection
+    let writer = Arc::new(smol::lock::Mutex::new(conn.clone()));
+    let (secret_tx, secret_rx) = smol::channel::unbounded();
     let (err_tx, err_rx) = smol::channel::bounded(1);
     let exec = smol::Executor::new();
 
+    // - stock greeters don't support any protocol extensions until they tell us otherwise
+    let extensions = Arc::new(AtomicU32::new(0));
+
+    // - forward any notifications from the controller to the greeter
+    let forward_notifs = || {
+        let notifs = controller.subscribe_notifications();
+        let writer = writer.clone();
+        let err_tx = err_tx.clone();
+        let extensions = extensions.clone();
+        exec.spawn(async move {
+            while let Ok(notif) = notifs.recv().await {
+                if matches!(notif, GreeterNotification::DeviceStatus { .. })
+                    && extensions.load(Ordering::Acquire) & Extension::DeviceStatus as u32 == 0
+                {
+                    continue;
+                }
+
+                if let Err(err) = send_notification(&writer, notif).await {
+                    _ = err_tx.try_send(err.into());
+                    return;
+                }
+            }
+        })
+    };
+    let mut notif_task = forward_notifs();
+
+    let mut login_task: Option<smol::Task<()>> = None;
     let main_loop = async {
-        let mut login_task = None;
         loop {
-            match recv_msg(&mut conn).await? {
+            match GreeterMessage::read_from(&mut conn).await? {
                 //Login requests
-                Some(msg) if msg == GreeterMessage::Login as u32 => {
-                    //Read the username / password
-                    let user = recv_string(&mut conn).await?;
-                    let password = Zeroizing::new(recv_string(&mut conn).await?);
-
-                    //Read the session the user selected
-                    let mut _ses_type = [0u8; 4];
-                    conn.read_exact(&mut _ses_type).await?;
-
-                    let session = recv_string(&mut conn).await?;
-
+                Some(GreeterMessage::Login {
+                    user,
+                    password,
+                    session_type,
+                    session,
+                }) => {
                     //Forward the request to the controller
                     if !login_task.as_ref().is_none_or(smol::Task::is_finished) {
                         println!("ignoring concurrent login request for user {user:?}");
@@ -115,15 +386,21 @@ async fn greeter_control_connection(
 
                     println!("handling login request from greeter for user {user:?}");
 
-                    let conn = conn.clone();
+                    let prompter = GreeterPrompter {
+                        stream: writer.clone(),
+                        extensions: extensions.clone(),
+                        secret_rx: secret_rx.clone(),
+                        error: std::sync::Mutex::new(None),
+                    };
                     let err_tx = err_tx.clone();
                     let controller = controller.clone();
                     login_task = Some(exec.spawn(async move {
                         if let Err(err) = handle_login_request(
-                            conn,
+                            &prompter,
                             &user,
                             password,
                             Path::new(&*session),
+                            session_type,
                             &*controller,
                         )
                         .await
@@ -133,222 +410,129 @@ async fn greeter_control_connection(
                     }));
                 }
 
-                //Power action messages
-                Some(msg)
-                    if PowerAction::ALL_ACTIONS
-                        .into_iter()
-                        .any(|a| msg == GreeterMessage::from(a) as u32) =>
-                {
-                    let act = PowerAction::ALL_ACTIONS
-                        .into_iter()
-                        .find(|&a| msg == GreeterMessage::from(a) as u32)
-                        .unwrap();
+                //Requests to continue without unlocking a device
+                Some(GreeterMessage::SkipDevice(device)) => {
+                    controller.skip_device(&device).await;
+                }
+
+                //Responses to secret prompts issued by the controller
+                Some(GreeterMessage::SecretResponse(secret)) => {
+                    _ = secret_tx.try_send(secret);
+                }
 
-                    controller.perform_power_action(act).await;
+                //Protocol extensions supported by the greeter
+                Some(GreeterMessage::Extensions(exts)) => {
+                    println!("greeter supports protocol extensions {exts:#x}");
+
+                    let prev_exts = extensions.swap(exts, Ordering::AcqRel);
+
+                    // - resubscribe so that the greeter is told about the current device status
+                    if exts & !prev_exts & Extension::DeviceStatus as u32 != 0 {
+                        notif_task = forward_notifs();
+                    }
+                }
+
+                //Power action messages
+                Some(GreeterMessage::PowerOff) => {
+                    controller.perform_power_action(PowerAction::PowerOff).await
+                }
+                Some(GreeterMessage::Reboot) => {
+                    controller.perform_power_action(PowerAction::Reboot).await
+                }
+                Some(GreeterMessage::Suspend) => {
+                    controller.perform_power_action(PowerAction::Suspend).await
+                }
+                Some(GreeterMessage::Hibernate) => {
+                    controller
+                        .perform_power_action(PowerAction::Hibernate)
+                        .await
+                }
+                Some(GreeterMessage::HybridSleep) => {
+                    controller
+                        .perform_power_action(PowerAction::HybridSleep)
+                        .await
                 }
 
-                Some(msg) => bail!("unknown greeter control message {msg}"),
+                Some(msg) => bail!("unexpected greeter control message {msg:?}"),
                 None => return Ok(()),
             }
         }
     };
 
-    exec.run(main_loop.or(async { Err(err_rx.recv().await.unwrap()) }))
-        .await
+    let res = exec
+        .run(main_loop.or(async { Err(err_rx.recv().await.unwrap()) }))
+        .await;
+
+    //Cancel any login which is still in progress, so that it doesn't keep answering password requests for a greeter which is gone
+    // - the controller keeps the password request it was processing around for the next greeter connection
+    if let Some(task) = login_task.take()
+        && !task.is_finished()
+    {
+        println!("greeter connection closed during a login; cancelling it");
+        exec.run(task.cancel()).await;
+    }
+
+    res
 }
 
 async fn handle_login_request(
-    stream: impl AsyncWrite + Send + Sync + Unpin,
+    greeter: &GreeterPrompter,
     user: &str,
     password: Zeroizing<Box<str>>,
     session: &Path,
+    session_type: SessionType,
     controller: &impl GreeterController,
 ) -> Result<()> {
-    let stream = smol::lock::Mutex::new(stream);
-
-    let msg_exec = smol::Executor::new();
-    let login_ok = msg_exec
-        .run(async {
-            //Setup information message handling
-            let mut msg_tasks = Vec::new();
-            let msg_sender = |msg: &str| {
-                let msg = msg.to_string();
-                msg_tasks.push(msg_exec.spawn(async {
-                    let msg = msg; // - move msg into the closure
-
-                    // - send the message to the greeter
-                    let mut stream = stream.lock().await;
-                    stream
-                        .write_all(&u32::to_be_bytes(DaemonMessage::InformationMessage as u32))
-                        .await?;
-                    send_string(stream.deref_mut(), &msg).await?;
-
-                    Ok::<_, anyhow::Error>(())
-                }));
-            };
-
-            //Invoke the controller
-            let login_ok = controller.login(user, password, session, msg_sender).await;
-
-            println!(
-                "finished handling login request for user {user:?}, result: {}",
-                if login_ok { "OK" } else { "failure" }
-            );
-
-            //Finish sending information messages
-            for t in msg_tasks {
-                t.await?;
-            }
-
-            Ok::<_, anyhow::Error>(login_ok)
-        })
-        .await?;
-
-    drop(msg_exec);
-
-    //Reply with the correct answer message
-    stream
-        .into_inner()
-        .write_all(&u32::to_be_bytes(if login_ok {
-            DaemonMessage::LoginSucceeded
-        } else {
-            DaemonMessage::LoginFailed
-        } as u32))
-        .await?;
-
-    Ok(())
-}
-
-async fn recv_msg(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<u32>> {
-    let mut buf = [0u8; 4];
-    match stream.read_exact(&mut buf).await {
-        Ok(_) => Ok(Some(u32::from_be_bytes(buf))),
-        Err(err)
-            if matches!(
-                err.kind(),
-                ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset
-            ) =>
-        {
-            Ok(None)
-        }
-        Err(err) => Err(err),
-    }
-}
-
-async fn recv_string(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Box<str>> {
-    //Read the string length
-    let mut len = {
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf).await?;
-        u32::from_be_bytes(buf) as usize
-    };
-
-    if len == u32::MAX as usize {
-        //Null marker
-        return Ok(Box::from(""));
-    } else if len == (u32::MAX - 1) as usize {
-        //Extended length
-        let mut buf = [0u8; 8];
-        stream.read_exact(&mut buf).await?;
-        len = u64::from_be_bytes(buf) as usize;
-    }
-
-    //Read the codepoints
-    if len % 2 != 0 {
-        return Err(std::io::ErrorKind::InvalidData.into());
-    }
-
-    // - the data we're reading might be sensitive, so be cautious and zeroize just in case
-    let mut cps = Zeroizing::new(vec![0u16; len / 2]);
-
-    stream
-        .read_exact(unsafe {
-            std::slice::from_raw_parts_mut(cps.as_mut_ptr().cast(), cps.len() * 2)
-        })
-        .await?;
-
-    #[cfg(target_endian = "little")]
-    cps.iter_mut().for_each(|w| *w = w.swap_bytes());
-
-    //Construct the string from its codepoints, taking great care to not leave any stale codepoints in memory
-    let mut str_len = 0;
-    for c in char::decode_utf16(cps.iter().cloned()) {
-        match c {
-            Ok(c) => str_len += c.len_utf8(),
-            Err(_) => return Err(std::io::ErrorKind::InvalidData.into()),
+    //Invoke the controller
+    let res = controller
+        .login(user, password, session, session_type, greeter)
+        .await;
+
+    match &res {
+        Ok(()) => println!("finished handling login request for user {user:?}, result: OK"),
+        Err(err) => {
+            eprintln!("finished handling login request for user {user:?}, result: {err}");
+            greeter.send_message(&err.to_string()).await;
         }
     }
 
-    let mut s = Box::new_uninit_slice(str_len);
-    s.fill(MaybeUninit::zeroed());
-    let mut s = unsafe { s.assume_init() };
-
-    let mut off = 0;
-    for c in char::decode_utf16(cps.iter().cloned()) {
-        let c = c.unwrap();
-        c.encode_utf8(&mut s[off..]);
-        off += c.len_utf16();
+    // - propagate any errors we encountered while talking to the greeter
+    if let Some(err) = greeter.error.lock().unwrap().take() {
+        return Err(err.into());
     }
 
-    Ok(unsafe { str::from_boxed_utf8_unchecked(s) })
-}
-
-async fn send_string(stream: &mut (impl AsyncWrite + Unpin), val: &str) -> std::io::Result<()> {
-    let cps: Vec<u16> = val.encode_utf16().map(|w| w.to_be()).collect();
-    let cps: &[u8] = unsafe { std::slice::from_raw_parts(cps.as_ptr().cast(), cps.len() * 2) };
-
-    if !cps.is_empty() {
-        if cps.len() < (u32::MAX - 1) as usize {
-            stream
-                .write_all(&u32::to_be_bytes(cps.len() as u32))
-                .await?;
-        } else {
-            stream.write_all(&u32::to_be_bytes(u32::MAX - 1)).await?;
-            stream
-                .write_all(&u64::to_be_bytes(cps.len() as u64))
-                .await?;
-        }
-
-        stream.write_all(cps).await?;
+    //Reply with the correct answer message
+    if res.is_ok() {
+        DaemonMessage::LoginSucceeded
     } else {
-        stream.write_all(&u32::MAX.to_be_bytes()).await?;
+        DaemonMessage::LoginFailed
     }
+    .write_to(greeter.stream.lock().await.deref_mut())
+    .await?;
 
     Ok(())
 }
 
-#[repr(u32)]
-#[derive(Debug, Clone, Copy)]
-enum GreeterMessage {
-    Connect = 0,
-    Login,
-    PowerOff,
-    Reboot,
-    Suspend,
-    Hibernate,
-    HybridSleep,
-}
-
-impl From<PowerAction> for GreeterMessage {
-    fn from(value: PowerAction) -> Self {
-        match value {
-            PowerAction::PowerOff => Self::PowerOff,
-            PowerAction::Reboot => Self::Reboot,
-            PowerAction::Suspend => Self::Suspend,
-            PowerAction::Hibernate => Self::Hibernate,
-            PowerAction::HybridSleep => Self::HybridSleep,
-        }
-    }
-}
+async fn send_notification(
+    stream: &smol::lock::Mutex<impl AsyncWrite + Unpin>,
+    notif: GreeterNotification,
+) -> std::io::Result<()> {
+    let msg = match notif {
+        GreeterNotification::Information(msg) => DaemonMessage::InformationMessage(msg.into()),
+        GreeterNotification::DeviceStatus {
+            index,
+            count,
+            device,
+            status,
+        } => DaemonMessage::DeviceStatus {
+            index: index as u32,
+            count: count as u32,
+            status,
+            device: device.into(),
+        },
+    };
 
-#[repr(u32)]
-#[derive(Debug, Clone, Copy)]
-enum DaemonMessage {
-    HostName,
-    Capabilities,
-    LoginSucceeded,
-    LoginFailed,
-    InformationMessage,
+    msg.write_to(stream.lock().await.deref_mut()).await
 }
 
 #[repr(u32)]
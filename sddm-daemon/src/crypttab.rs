@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+
+use crate::device_spec::{DeviceMatch, DeviceSpec};
+
+pub struct CrypttabEntry {
+    pub volume: String,
+    pub device: DeviceSpec,
+    pub options: Vec<String>,
+}
+
+impl CrypttabEntry {
+    pub fn load() -> Result<Vec<CrypttabEntry>> {
+        const CRYPTTAB: &str = "/etc/crypttab";
+
+        if !std::fs::exists(CRYPTTAB).context("failed to check for crypttab")? {
+            return Ok(Vec::new());
+        }
+
+        let crypttab = std::fs::read_to_string(CRYPTTAB).context("failed to read crypttab")?;
+
+        //Each line has the format `<volume> <device> [<key file>] [<options>]`
+        Ok(crypttab
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut fields = l.split_whitespace();
+                let volume = fields.next()?.to_owned();
+                let device = DeviceSpec::parse(fields.next()?);
+                let options = fields
+                    .nth(1)
+                    .map(|o| o.split(',').map(String::from).collect())
+                    .unwrap_or_default();
+
+                Some(CrypttabEntry {
+                    volume,
+                    device,
+                    options,
+                })
+            })
+            .collect())
+    }
+
+    pub fn find<'a>(
+        entries: &'a [CrypttabEntry],
+        device: &DeviceSpec,
+    ) -> Option<&'a CrypttabEntry> {
+        // - prefer exact matches, since they don't depend on udev links being present
+        entries.iter().find(|e| e.device == *device).or_else(|| {
+            entries
+                .iter()
+                .find(|e| e.device.matches(device) == DeviceMatch::Match)
+        })
+    }
+
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find_map(|o| o.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    pub fn tries(&self) -> Option<u32> {
+        // - systemd-cryptsetup defaults to 3 tries, with 0 meaning unlimited
+        match self.option("tries").map_or(Ok(3), str::parse) {
+            Ok(0) => None,
+            Ok(tries) => Some(tries),
+            Err(_) => {
+                eprintln!(
+                    "malformed tries= option for crypttab volume {}",
+                    self.volume
+                );
+                None
+            }
+        }
+    }
+}
+
+pub fn cryptsetup_unit(volume: &str) -> String {
+    //Escape the volume name like `systemd-escape` does
+    let mut escaped = String::with_capacity(volume.len());
+    for (i, c) in volume.chars().enumerate() {
+        match c {
+            '/' => escaped.push('-'),
+            '.' if i == 0 => escaped.push_str("\\x2e"),
+            c if c.is_ascii_alphanumeric() || ":_.".contains(c) => escaped.push(c),
+            c => {
+                let mut buf = [0u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("\\x{b:02x}"));
+                }
+            }
+        }
+    }
+
+    format!("systemd-cryptsetup@{escaped}.service")
+}
@@ -0,0 +1,118 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+//A block device specification as used by crypttab / systemd-cryptsetup
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceSpec {
+    Path(PathBuf),
+    Uuid(String),
+    Label(String),
+    PartUuid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceMatch {
+    Match,
+    NoMatch,
+    Unresolved, // - one of the devices could not be resolved (yet)
+}
+
+impl DeviceSpec {
+    pub fn parse(spec: &str) -> DeviceSpec {
+        //Handle tagged specifications
+        if let Some(uuid) = spec.strip_prefix("UUID=") {
+            return DeviceSpec::Uuid(uuid.to_ascii_lowercase());
+        } else if let Some(label) = spec.strip_prefix("LABEL=") {
+            return DeviceSpec::Label(label.to_owned());
+        } else if let Some(uuid) = spec.strip_prefix("PARTUUID=") {
+            return DeviceSpec::PartUuid(uuid.to_ascii_lowercase());
+        }
+
+        //Normalize udev by-* symlinks into their tagged counterparts
+        let path = Path::new(spec);
+        if let Some(name) = path.file_name().and_then(|n| n.to_str())
+            && let Some(dir) = path.parent().and_then(|d| d.to_str())
+        {
+            match dir {
+                "/dev/disk/by-uuid" => return DeviceSpec::Uuid(name.to_ascii_lowercase()),
+                "/dev/disk/by-label" => return DeviceSpec::Label(unescape_udev(name)),
+                "/dev/disk/by-partuuid" => return DeviceSpec::PartUuid(name.to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+
+        DeviceSpec::Path(path.to_owned())
+    }
+
+    pub fn link_path(&self) -> PathBuf {
+        match self {
+            DeviceSpec::Path(path) => path.clone(),
+            DeviceSpec::Uuid(uuid) => Path::new("/dev/disk/by-uuid").join(uuid),
+            DeviceSpec::Label(label) => Path::new("/dev/disk/by-label").join(escape_udev(label)),
+            DeviceSpec::PartUuid(uuid) => Path::new("/dev/disk/by-partuuid").join(uuid),
+        }
+    }
+
+    pub fn resolve(&self) -> Option<PathBuf> {
+        //The link might not exist (yet) if udev didn't get around to processing the device
+        std::fs::canonicalize(self.link_path()).ok()
+    }
+
+    pub fn matches(&self, other: &DeviceSpec) -> DeviceMatch {
+        if self == other {
+            return DeviceMatch::Match;
+        }
+
+        match (self.resolve(), other.resolve()) {
+            (Some(a), Some(b)) if a == b => DeviceMatch::Match,
+            (Some(_), Some(_)) => DeviceMatch::NoMatch,
+            _ => DeviceMatch::Unresolved,
+        }
+    }
+}
+
+impl Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSpec::Path(path) => write!(f, "{}", path.display()),
+            DeviceSpec::Uuid(uuid) => write!(f, "UUID={uuid}"),
+            DeviceSpec::Label(label) => write!(f, "LABEL={label}"),
+            DeviceSpec::PartUuid(uuid) => write!(f, "PARTUUID={uuid}"),
+        }
+    }
+}
+
+//udev escapes unsafe characters in by-label links as `\xNN`
+fn escape_udev(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("\\x{:02x}", c as u32));
+        }
+    }
+    escaped
+}
+
+fn unescape_udev(name: &str) -> String {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rem = name.as_bytes();
+    while let Some((&b, rest)) = rem.split_first() {
+        if b == b'\\'
+            && let Some(hex) = rest.strip_prefix(b"x").and_then(|r| r.get(..2))
+            && let Some(c) = str::from_utf8(hex)
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            bytes.push(c);
+            rem = &rest[3..];
+        } else {
+            bytes.push(b);
+            rem = rest;
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
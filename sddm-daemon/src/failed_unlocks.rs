@@ -0,0 +1,202 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::file_flags::{FS_IMMUTABLE_FL, get_file_flags, set_file_flags};
+
+//The number of failed unlock attempts is kept in a vendor EFI variable, so that it persists across reboots
+// - this has to be kept in sync with the PAM module, which clears the counter once the login was handed off
+const FAILED_UNLOCKS_VAR: &str = "LUKSStage1SddmFailedUnlocks-c508b668-ea00-45bb-96b5-e0fb6908709e";
+
+const EFI_VARIABLE_NON_VOLATILE: u32 = 0x1;
+const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+//Returns 0 if the variable doesn't exist
+pub fn read_failed_unlocks(efivarfs: &Path) -> Result<u32> {
+    let path = efivarfs.join(FAILED_UNLOCKS_VAR);
+    if !std::fs::exists(&path).context("failed to check for failed unlock counter EFI variable")? {
+        return Ok(0);
+    }
+
+    //efivarfs files consist of the variable's attributes, followed by its data
+    let var = std::fs::read(path).context("failed to read failed unlock counter EFI variable")?;
+    Ok(var
+        .get(4..8)
+        .map_or(0, |d| u32::from_le_bytes(d.try_into().unwrap())))
+}
+
+//Returns the new count, or `None` if efivarfs isn't available (e.g. because we weren't booted using EFI)
+pub fn increment_failed_unlocks(efivarfs: &Path) -> Result<Option<u32>> {
+    if !efivarfs.is_dir() {
+        return Ok(None);
+    }
+
+    // - don't wear out the NVRAM once the counter saturated
+    let count = read_failed_unlocks(efivarfs)?;
+    if count == u32::MAX {
+        return Ok(Some(count));
+    }
+    let count = count + 1;
+
+    let mut var = Vec::with_capacity(8);
+    var.extend_from_slice(
+        &(EFI_VARIABLE_NON_VOLATILE
+            | EFI_VARIABLE_BOOTSERVICE_ACCESS
+            | EFI_VARIABLE_RUNTIME_ACCESS)
+            .to_le_bytes(),
+    );
+    var.extend_from_slice(&count.to_le_bytes());
+
+    //efivarfs marks variables of unknown vendors immutable, so lift that while we're rewriting the variable (like systemd does)
+    let path = efivarfs.join(FAILED_UNLOCKS_VAR);
+    let immutable = ImmutableGuard::lift(&path)?;
+
+    // - efivarfs requires the variable to be written using a single write call, and doesn't support truncating it
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .and_then(|mut f| f.write(&var))
+        .context("failed to write failed unlock counter EFI variable")?;
+    anyhow::ensure!(
+        written == var.len(),
+        "short write to failed unlock counter EFI variable"
+    );
+
+    drop(immutable);
+    Ok(Some(count))
+}
+
+//Clears the immutable flag of a file, and restores it once dropped
+struct ImmutableGuard(Option<PathBuf>);
+
+impl ImmutableGuard {
+    fn lift(path: &Path) -> Result<ImmutableGuard> {
+        let file = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ImmutableGuard(None));
+            }
+            Err(err) => {
+                return Err(err).context("failed to open failed unlock counter EFI variable");
+            }
+        };
+
+        let Some(flags) =
+            get_file_flags(&file).context("failed to query failed unlock counter file flags")?
+        else {
+            return Ok(ImmutableGuard(None));
+        };
+
+        if flags & FS_IMMUTABLE_FL == 0 {
+            return Ok(ImmutableGuard(None));
+        }
+
+        set_file_flags(&file, flags & !FS_IMMUTABLE_FL)
+            .context("failed to clear immutable flag of failed unlock counter EFI variable")?;
+
+        Ok(ImmutableGuard(Some(path.to_owned())))
+    }
+}
+
+impl Drop for ImmutableGuard {
+    fn drop(&mut self) {
+        let Some(path) = self.0.take() else {
+            return;
+        };
+
+        let res = std::fs::File::open(&path).and_then(|file| match get_file_flags(&file)? {
+            Some(flags) => set_file_flags(&file, flags | FS_IMMUTABLE_FL),
+            None => Ok(()),
+        });
+
+        if let Err(err) = res {
+            eprintln!("failed to restore immutable flag of {path:?}: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_variable_reads_as_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_failed_unlocks(dir.path()).unwrap(), 0);
+    }
+
+    #[test]
+    fn increment_writes_attributes_and_count() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(increment_failed_unlocks(dir.path()).unwrap(), Some(1));
+        assert_eq!(increment_failed_unlocks(dir.path()).unwrap(), Some(2));
+        assert_eq!(read_failed_unlocks(dir.path()).unwrap(), 2);
+
+        let var = std::fs::read(dir.path().join(FAILED_UNLOCKS_VAR)).unwrap();
+        assert_eq!(var, [0x7, 0, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn increment_lifts_and_restores_immutable_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        increment_failed_unlocks(dir.path()).unwrap();
+
+        // - setting the immutable flag requires CAP_LINUX_IMMUTABLE and filesystem support
+        let file = std::fs::File::open(dir.path().join(FAILED_UNLOCKS_VAR)).unwrap();
+        let Ok(Some(flags)) = get_file_flags(&file) else {
+            return;
+        };
+        if set_file_flags(&file, flags | FS_IMMUTABLE_FL).is_err() {
+            return;
+        }
+
+        assert_eq!(increment_failed_unlocks(dir.path()).unwrap(), Some(2));
+        assert_eq!(read_failed_unlocks(dir.path()).unwrap(), 2);
+
+        let flags = get_file_flags(&file).unwrap().unwrap();
+        assert_ne!(flags & FS_IMMUTABLE_FL, 0);
+
+        // - otherwise the scratch directory can't be removed
+        set_file_flags(&file, flags & !FS_IMMUTABLE_FL).unwrap();
+    }
+
+    #[test]
+    fn saturated_count_is_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FAILED_UNLOCKS_VAR);
+
+        let mut var = 0x7u32.to_le_bytes().to_vec();
+        var.extend_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &var).unwrap();
+
+        // - any write would bump the modification time
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        assert_eq!(
+            increment_failed_unlocks(dir.path()).unwrap(),
+            Some(u32::MAX)
+        );
+        assert_eq!(
+            file.metadata().unwrap().modified().unwrap(),
+            std::time::SystemTime::UNIX_EPOCH
+        );
+    }
+
+    #[test]
+    fn missing_efivarfs_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            increment_failed_unlocks(&dir.path().join("efivars")).unwrap(),
+            None
+        );
+    }
+}
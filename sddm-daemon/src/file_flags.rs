@@ -0,0 +1,26 @@
+//Inode flags of files, as shown by `lsattr`
+// - this is also used by the PAM module, so only depend on crates both of them use
+
+use std::{fs::File, os::fd::AsRawFd};
+
+use nix::{errno::Errno, libc::c_int};
+
+pub const FS_IMMUTABLE_FL: c_int = 0x10;
+
+nix::ioctl_read_bad!(fs_ioc_getflags, nix::libc::FS_IOC_GETFLAGS, c_int);
+nix::ioctl_write_ptr_bad!(fs_ioc_setflags, nix::libc::FS_IOC_SETFLAGS, c_int);
+
+// - returns `None` if the filesystem doesn't support file flags
+pub fn get_file_flags(file: &File) -> std::io::Result<Option<c_int>> {
+    let mut flags = 0;
+    match unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) } {
+        Ok(_) => Ok(Some(flags)),
+        Err(Errno::ENOTTY | Errno::EOPNOTSUPP) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn set_file_flags(file: &File, flags: c_int) -> std::io::Result<()> {
+    unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }?;
+    Ok(())
+}
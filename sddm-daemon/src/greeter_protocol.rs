@@ -0,0 +1,504 @@
+use std::io::{Error, ErrorKind, Result};
+
+use smol::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+//The SDDM greeter protocol consists of big-endian u32 message IDs followed by their payload
+// - strings are sent as UTF-16BE code units, prefixed by their length in bytes
+//   (u32::MAX marks a null string, u32::MAX - 1 is followed by an extended u64 length)
+// - messages past the stock SDDM protocol (daemon messages 5+, greeter messages 7+) are our own extensions;
+//   stock greeters can't skip unknown messages, so extended daemon messages may only be sent once the greeter advertised support for them
+
+//Limit the length of strings we're willing to receive, so that a greeter can't make us allocate arbitrary amounts of memory
+pub const MAX_STRING_LEN: usize = 64 * 1024;
+
+const NULL_STRING_LEN: u32 = u32::MAX;
+const EXTENDED_STRING_LEN: u32 = u32::MAX - 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GreeterMessage {
+    Connect,
+    Login {
+        user: Box<str>,
+        password: Zeroizing<Box<str>>,
+        session_type: SessionType,
+        session: Box<str>,
+    },
+    PowerOff,
+    Reboot,
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    SecretResponse(Zeroizing<Box<str>>),
+    Extensions(u32),
+    SkipDevice(Box<str>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DaemonMessage {
+    HostName(Box<str>),
+    Capabilities(u32),
+    LoginSucceeded,
+    LoginFailed,
+    InformationMessage(Box<str>),
+    SecretPrompt {
+        kind: SecretKind,
+        prompt: Box<str>,
+    },
+    DeviceStatus {
+        index: u32,
+        count: u32,
+        status: DeviceStatus,
+        device: Box<str>,
+    },
+}
+
+//Protocol extensions which a greeter can advertise support for
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    SecretPrompts = 0b01,
+    DeviceStatus = 0b10,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionType {
+    Unknown,
+    X11,
+    Wayland,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecretKind {
+    Pin,
+    Passphrase,
+}
+
+// - these values are part of the protocol, so never renumber them
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceStatus {
+    Waiting = 0,   // - no password request was received yet
+    Prompting = 1, // - a password request is waiting for the user to log in
+    Answered = 2,  // - we replied to the password request, and are waiting for the outcome
+    Unlocked = 3,
+    Failed = 4,  // - the password was rejected
+    Missing = 5, // - the device didn't show up within the configured timeout
+    Skipped = 6, // - the user chose to continue without unlocking the device
+}
+
+impl GreeterMessage {
+    //Returns `None` if the stream was closed before the next message
+    pub async fn read_from(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<Self>> {
+        let Some(id) = read_message_id(stream).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(match id {
+            0 => GreeterMessage::Connect,
+            1 => GreeterMessage::Login {
+                user: read_string(stream).await?,
+                password: Zeroizing::new(read_string(stream).await?),
+                session_type: match read_u32(stream).await? {
+                    0 => SessionType::Unknown,
+                    1 => SessionType::X11,
+                    2 => SessionType::Wayland,
+                    ty => return Err(invalid_data(format!("unknown session type {ty}"))),
+                },
+                session: read_string(stream).await?,
+            },
+            2 => GreeterMessage::PowerOff,
+            3 => GreeterMessage::Reboot,
+            4 => GreeterMessage::Suspend,
+            5 => GreeterMessage::Hibernate,
+            6 => GreeterMessage::HybridSleep,
+            7 => GreeterMessage::SecretResponse(Zeroizing::new(read_string(stream).await?)),
+            8 => GreeterMessage::Extensions(read_u32(stream).await?),
+            9 => GreeterMessage::SkipDevice(read_string(stream).await?),
+            id => return Err(invalid_data(format!("unknown greeter message {id}"))),
+        }))
+    }
+
+    // - the daemon never sends greeter messages; this is used by tests / the fuzz target
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            GreeterMessage::Connect => put_u32(buf, 0),
+            GreeterMessage::Login {
+                user,
+                password,
+                session_type,
+                session,
+            } => {
+                put_u32(buf, 1);
+                put_string(buf, user);
+                put_string(buf, password);
+                put_u32(buf, *session_type as u32);
+                put_string(buf, session);
+            }
+            GreeterMessage::PowerOff => put_u32(buf, 2),
+            GreeterMessage::Reboot => put_u32(buf, 3),
+            GreeterMessage::Suspend => put_u32(buf, 4),
+            GreeterMessage::Hibernate => put_u32(buf, 5),
+            GreeterMessage::HybridSleep => put_u32(buf, 6),
+            GreeterMessage::SecretResponse(secret) => {
+                put_u32(buf, 7);
+                put_string(buf, secret);
+            }
+            GreeterMessage::Extensions(exts) => {
+                put_u32(buf, 8);
+                put_u32(buf, *exts);
+            }
+            GreeterMessage::SkipDevice(device) => {
+                put_u32(buf, 9);
+                put_string(buf, device);
+            }
+        }
+    }
+}
+
+impl DaemonMessage {
+    //Returns `None` if the stream was closed before the next message
+    // - the daemon never receives daemon messages; this is used by tests / the fuzz target
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn read_from(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<Self>> {
+        let Some(id) = read_message_id(stream).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(match id {
+            0 => DaemonMessage::HostName(read_string(stream).await?),
+            1 => DaemonMessage::Capabilities(read_u32(stream).await?),
+            2 => DaemonMessage::LoginSucceeded,
+            3 => DaemonMessage::LoginFailed,
+            4 => DaemonMessage::InformationMessage(read_string(stream).await?),
+            5 => DaemonMessage::SecretPrompt {
+                kind: match read_u32(stream).await? {
+                    0 => SecretKind::Pin,
+                    1 => SecretKind::Passphrase,
+                    kind => return Err(invalid_data(format!("unknown secret kind {kind}"))),
+                },
+                prompt: read_string(stream).await?,
+            },
+            6 => DaemonMessage::DeviceStatus {
+                index: read_u32(stream).await?,
+                count: read_u32(stream).await?,
+                status: match read_u32(stream).await? {
+                    0 => DeviceStatus::Waiting,
+                    1 => DeviceStatus::Prompting,
+                    2 => DeviceStatus::Answered,
+                    3 => DeviceStatus::Unlocked,
+                    4 => DeviceStatus::Failed,
+                    5 => DeviceStatus::Missing,
+                    6 => DeviceStatus::Skipped,
+                    status => return Err(invalid_data(format!("unknown device status {status}"))),
+                },
+                device: read_string(stream).await?,
+            },
+            id => return Err(invalid_data(format!("unknown daemon message {id}"))),
+        }))
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            DaemonMessage::HostName(hostname) => {
+                put_u32(buf, 0);
+                put_string(buf, hostname);
+            }
+            DaemonMessage::Capabilities(caps) => {
+                put_u32(buf, 1);
+                put_u32(buf, *caps);
+            }
+            DaemonMessage::LoginSucceeded => put_u32(buf, 2),
+            DaemonMessage::LoginFailed => put_u32(buf, 3),
+            DaemonMessage::InformationMessage(msg) => {
+                put_u32(buf, 4);
+                put_string(buf, msg);
+            }
+            DaemonMessage::SecretPrompt { kind, prompt } => {
+                put_u32(buf, 5);
+                put_u32(buf, *kind as u32);
+                put_string(buf, prompt);
+            }
+            DaemonMessage::DeviceStatus {
+                index,
+                count,
+                status,
+                device,
+            } => {
+                put_u32(buf, 6);
+                put_u32(buf, *index);
+                put_u32(buf, *count);
+                put_u32(buf, *status as u32);
+                put_string(buf, device);
+            }
+        }
+    }
+
+    pub async fn write_to(&self, stream: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        stream.write_all(&buf).await
+    }
+}
+
+async fn read_message_id(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<u32>> {
+    match read_u32(stream).await {
+        Ok(id) => Ok(Some(id)),
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn read_u32(stream: &mut (impl AsyncRead + Unpin)) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+async fn read_string(stream: &mut (impl AsyncRead + Unpin)) -> Result<Box<str>> {
+    //Read the string length
+    let len = match read_u32(stream).await? {
+        NULL_STRING_LEN => return Ok(Box::from("")),
+        EXTENDED_STRING_LEN => {
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf).await?;
+            u64::from_be_bytes(buf)
+        }
+        len => len as u64,
+    };
+
+    if len > MAX_STRING_LEN as u64 {
+        return Err(invalid_data(format!("string length {len} exceeds limit")));
+    }
+
+    let len = len as usize;
+    if !len.is_multiple_of(2) {
+        return Err(invalid_data("odd UTF-16 string length".into()));
+    }
+
+    //Read the code units
+    // - the data we're reading might be sensitive, so be cautious and zeroize just in case
+    let mut data = Zeroizing::new(vec![0u8; len]);
+    stream.read_exact(&mut data).await?;
+
+    let code_units = || {
+        data.chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+    };
+
+    //Construct the string from its code units, taking great care to not leave any stale copies in memory
+    // - size the string up front, so that it never has to be reallocated
+    let mut str_len = 0;
+    for c in char::decode_utf16(code_units()) {
+        match c {
+            Ok(c) => str_len += c.len_utf8(),
+            Err(_) => return Err(invalid_data("malformed UTF-16 string".into())),
+        }
+    }
+
+    let mut s = String::with_capacity(str_len);
+    s.extend(char::decode_utf16(code_units()).map(|c| c.unwrap()));
+    Ok(s.into_boxed_str())
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_be_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, val: &str) {
+    let len = val.encode_utf16().count() * 2;
+    if len == 0 {
+        put_u32(buf, NULL_STRING_LEN);
+        return;
+    }
+
+    if len < EXTENDED_STRING_LEN as usize {
+        put_u32(buf, len as u32);
+    } else {
+        put_u32(buf, EXTENDED_STRING_LEN);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    buf.reserve(len);
+    for w in val.encode_utf16() {
+        buf.extend_from_slice(&w.to_be_bytes());
+    }
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_greeter(data: &[u8]) -> Result<Option<GreeterMessage>> {
+        smol::block_on(GreeterMessage::read_from(&mut &data[..]))
+    }
+
+    fn decode_daemon(data: &[u8]) -> Result<Option<DaemonMessage>> {
+        smol::block_on(DaemonMessage::read_from(&mut &data[..]))
+    }
+
+    #[test]
+    fn greeter_message_round_trip() {
+        let msgs = [
+            GreeterMessage::Connect,
+            GreeterMessage::Login {
+                user: "alice".into(),
+                password: Zeroizing::new("hunter2 \u{1f511}".into()),
+                session_type: SessionType::Wayland,
+                session: "plasma.desktop".into(),
+            },
+            GreeterMessage::PowerOff,
+            GreeterMessage::Reboot,
+            GreeterMessage::Suspend,
+            GreeterMessage::Hibernate,
+            GreeterMessage::HybridSleep,
+            GreeterMessage::SecretResponse(Zeroizing::new("123456".into())),
+            GreeterMessage::Extensions(
+                Extension::SecretPrompts as u32 | Extension::DeviceStatus as u32,
+            ),
+            GreeterMessage::SkipDevice("UUID=0123-4567".into()),
+        ];
+
+        for msg in msgs {
+            let mut buf = Vec::new();
+            msg.encode(&mut buf);
+            assert_eq!(decode_greeter(&buf).unwrap(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn daemon_message_round_trip() {
+        let msgs = [
+            DaemonMessage::HostName("host".into()),
+            DaemonMessage::Capabilities(0b10101),
+            DaemonMessage::LoginSucceeded,
+            DaemonMessage::LoginFailed,
+            DaemonMessage::InformationMessage("wrong password for /dev/sda2".into()),
+            DaemonMessage::SecretPrompt {
+                kind: SecretKind::Pin,
+                prompt: "Please enter the token PIN:".into(),
+            },
+            DaemonMessage::DeviceStatus {
+                index: 1,
+                count: 2,
+                status: DeviceStatus::Unlocked,
+                device: "LABEL=räum".into(),
+            },
+            DaemonMessage::DeviceStatus {
+                index: 0,
+                count: 1,
+                status: DeviceStatus::Skipped,
+                device: "/dev/sda2".into(),
+            },
+        ];
+
+        for msg in msgs {
+            let mut buf = Vec::new();
+            msg.encode(&mut buf);
+            assert_eq!(decode_daemon(&buf).unwrap(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn multiple_messages_in_stream() {
+        let mut buf = Vec::new();
+        GreeterMessage::Connect.encode(&mut buf);
+        GreeterMessage::SkipDevice("/dev/sda2".into()).encode(&mut buf);
+
+        let mut stream = &buf[..];
+        smol::block_on(async {
+            assert_eq!(
+                GreeterMessage::read_from(&mut stream).await.unwrap(),
+                Some(GreeterMessage::Connect)
+            );
+            assert_eq!(
+                GreeterMessage::read_from(&mut stream).await.unwrap(),
+                Some(GreeterMessage::SkipDevice("/dev/sda2".into()))
+            );
+            assert_eq!(GreeterMessage::read_from(&mut stream).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn empty_strings_are_sent_as_null() {
+        let mut buf = Vec::new();
+        GreeterMessage::SkipDevice("".into()).encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 9, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            decode_greeter(&buf).unwrap(),
+            Some(GreeterMessage::SkipDevice("".into()))
+        );
+    }
+
+    #[test]
+    fn extended_string_length() {
+        let mut buf = vec![0, 0, 0, 9, 0xff, 0xff, 0xff, 0xfe];
+        buf.extend_from_slice(&4u64.to_be_bytes());
+        buf.extend_from_slice(&[0, b'h', 0, b'i']);
+
+        assert_eq!(
+            decode_greeter(&buf).unwrap(),
+            Some(GreeterMessage::SkipDevice("hi".into()))
+        );
+    }
+
+    #[test]
+    fn oversized_strings_are_rejected() {
+        // - this must fail before attempting to allocate / read the string
+        let mut buf = vec![0, 0, 0, 9, 0xff, 0xff, 0xff, 0xfe];
+        buf.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            decode_greeter(&buf).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let mut buf = vec![0, 0, 0, 9];
+        buf.extend_from_slice(&(MAX_STRING_LEN as u32 + 2).to_be_bytes());
+        assert_eq!(
+            decode_greeter(&buf).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        // - unknown message ID
+        assert!(decode_greeter(&[0, 0, 0, 42]).is_err());
+
+        // - odd string length
+        assert!(decode_greeter(&[0, 0, 0, 9, 0, 0, 0, 1, 0]).is_err());
+
+        // - unpaired surrogate
+        assert!(decode_greeter(&[0, 0, 0, 9, 0, 0, 0, 2, 0xd8, 0x00]).is_err());
+
+        // - truncated payload
+        assert!(decode_greeter(&[0, 0, 0, 9, 0, 0, 0, 4, 0, b'h']).is_err());
+
+        // - unknown session type
+        let mut buf = Vec::new();
+        GreeterMessage::Login {
+            user: "alice".into(),
+            password: Zeroizing::new("hunter2".into()),
+            session_type: SessionType::X11,
+            session: "plasma.desktop".into(),
+        }
+        .encode(&mut buf);
+        let ty_offset = buf.len() - 4 - 2 * "plasma.desktop".len() - 4;
+        buf[ty_offset..ty_offset + 4].copy_from_slice(&7u32.to_be_bytes());
+        assert!(decode_greeter(&buf).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        Arc,
+        atomic::{AtomicI32, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use smol::process::{Child, Command};
+
+use crate::sddm_config::SddmConfig;
+
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+//Runs the SDDM greeter, and restarts it if it crashes
+pub struct GreeterSupervisor {
+    greeter: PathBuf,
+    theme: Option<PathBuf>,
+    socket_path: PathBuf,
+    sddm_config_path: PathBuf,
+    max_restarts: u32,
+
+    child: Child,
+    pid: Arc<AtomicI32>, // - shared with the control server, which only accepts connections from the current greeter
+}
+
+impl GreeterSupervisor {
+    pub fn spawn(
+        sddm_config: &SddmConfig,
+        sddm_config_path: &Path,
+        socket_path: &Path,
+    ) -> Result<GreeterSupervisor> {
+        let greeter = sddm_config.greeter.clone();
+        let theme = sddm_config.theme.clone();
+
+        let child = spawn_greeter(&greeter, theme.as_deref(), socket_path, sddm_config_path)?;
+        let pid = Arc::new(AtomicI32::new(child.id() as i32));
+
+        Ok(GreeterSupervisor {
+            greeter,
+            theme,
+            socket_path: socket_path.to_owned(),
+            sddm_config_path: sddm_config_path.to_owned(),
+            max_restarts: sddm_config.greeter_restarts,
+            child,
+            pid,
+        })
+    }
+
+    pub fn pid(&self) -> Arc<AtomicI32> {
+        self.pid.clone()
+    }
+
+    //Waits for the greeter to exit, restarting it with an increasing delay if it crashes
+    // - only returns once the greeter exited successfully, or it crashed too often
+    pub async fn supervise(&mut self) -> ExitStatus {
+        let mut restarts = 0;
+        let mut delay = INITIAL_RESTART_DELAY;
+        loop {
+            let status = self
+                .child
+                .status()
+                .await
+                .expect("failed to wait for SDDM greeter");
+
+            if status.success() {
+                return status;
+            }
+
+            if restarts >= self.max_restarts {
+                eprintln!(
+                    "greeter exited with status {status}; giving up after {restarts} restarts"
+                );
+                return status;
+            }
+
+            restarts += 1;
+            eprintln!(
+                "greeter exited with status {status}; restarting it in {}s (restart {restarts}/{})",
+                delay.as_secs(),
+                self.max_restarts
+            );
+
+            smol::Timer::after(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+            // - if we can't respawn the greeter, then report its last exit status
+            match spawn_greeter(
+                &self.greeter,
+                self.theme.as_deref(),
+                &self.socket_path,
+                &self.sddm_config_path,
+            ) {
+                Ok(child) => {
+                    self.pid.store(child.id() as i32, Ordering::Release);
+                    self.child = child;
+                }
+                Err(err) => {
+                    eprintln!("failed to restart SDDM greeter: {err:#}");
+                    return status;
+                }
+            }
+        }
+    }
+
+    pub fn kill(&mut self) {
+        _ = self.child.kill();
+    }
+
+    pub async fn status(&mut self) -> ExitStatus {
+        self.child
+            .status()
+            .await
+            .expect("failed to wait for SDDM greeter")
+    }
+}
+
+fn spawn_greeter(
+    greeter: &Path,
+    theme: Option<&Path>,
+    socket_path: &Path,
+    sddm_config_path: &Path,
+) -> Result<Child> {
+    let mut cmd = Command::new(greeter);
+    cmd.arg("--socket")
+        .arg(socket_path)
+        .env("SDDM_CONFIG", sddm_config_path);
+
+    // - if we have a theme configured, pass that to the greeter
+    if let Some(theme) = theme {
+        cmd.arg("--theme").arg(theme);
+    }
+
+    cmd.spawn().context("failed to start SDDM greeter")
+}
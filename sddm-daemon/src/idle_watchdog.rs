@@ -0,0 +1,84 @@
+use std::{os::fd::AsFd, path::PathBuf, time::Duration};
+
+use evdev::{AbsoluteAxisCode, EventType, KeyCode, RelativeAxisCode};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+
+//Returns a channel which receives a message whenever a keyboard / pointing device reports events
+pub fn watch_input_activity() -> smol::channel::Receiver<()> {
+    const RESCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+    let (tx, rx) = smol::channel::bounded(1);
+
+    std::thread::spawn(move || {
+        let mut devs: Vec<(PathBuf, evdev::Device)> = Vec::new();
+        while !tx.is_closed() {
+            //Pick up any new devices
+            for (path, dev) in evdev::enumerate() {
+                if is_user_input_device(&dev) && !devs.iter().any(|(p, _)| *p == path) {
+                    devs.push((path, dev));
+                }
+            }
+
+            //Wait until any device reports events, or it's time to rescan
+            let mut fds: Vec<_> = devs
+                .iter()
+                .map(|(_, dev)| PollFd::new(dev.as_fd(), PollFlags::POLLIN))
+                .collect();
+
+            let timeout = PollTimeout::try_from(RESCAN_INTERVAL).unwrap();
+            if let Err(err) = poll(&mut fds, timeout) {
+                eprintln!("failed to poll evdev devices for input activity: {err}");
+                std::thread::sleep(RESCAN_INTERVAL);
+                continue;
+            }
+
+            let ready: Vec<_> = fds
+                .iter()
+                .map(|fd| fd.revents().is_some_and(|r| !r.is_empty()))
+                .collect();
+            drop(fds);
+
+            let mut activity = false;
+            let mut idx = 0;
+            devs.retain_mut(|(_, dev)| {
+                let is_ready = ready[idx];
+                idx += 1;
+
+                if !is_ready {
+                    return true;
+                }
+
+                // - if reading fails, the device went away; pick it up again if it comes back
+                activity = true;
+                dev.fetch_events().is_ok()
+            });
+
+            if activity && let Err(smol::channel::TrySendError::Closed(_)) = tx.try_send(()) {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+//Only keyboards and pointing devices indicate that someone is around; other devices (e.g. accelerometers, lid switches) report events on their own
+fn is_user_input_device(dev: &evdev::Device) -> bool {
+    let keys = dev.supported_keys();
+    let has_key = |key| keys.is_some_and(|k| k.contains(key));
+
+    let is_keyboard = dev.supported_events().contains(EventType::KEY)
+        && dev.supported_events().contains(EventType::REPEAT)
+        && has_key(KeyCode::KEY_ESC)
+        && has_key(KeyCode::KEY_ENTER);
+
+    let is_mouse = dev.supported_relative_axes().is_some_and(|a| {
+        a.contains(RelativeAxisCode::REL_X) && a.contains(RelativeAxisCode::REL_Y)
+    }) && has_key(KeyCode::BTN_LEFT);
+
+    let is_touch = dev.supported_absolute_axes().is_some_and(|a| {
+        a.contains(AbsoluteAxisCode::ABS_X) && a.contains(AbsoluteAxisCode::ABS_Y)
+    }) && (has_key(KeyCode::BTN_TOUCH) || has_key(KeyCode::BTN_LEFT));
+
+    is_keyboard || is_mouse || is_touch
+}
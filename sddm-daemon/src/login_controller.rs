@@ -1,48 +1,152 @@
+use std::ffi::OsStr;
 use std::path::PathBuf;
-use std::{collections::HashSet, path::Path};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, path::Path};
 
 use crate::power_actions::{PowerAction, PowerActionClient};
 
 use crate::{
-    control_server::GreeterController, password_agent::PasswordRequest, sddm_config::SddmConfig,
+    control_server::{
+        GreeterController, GreeterNotification, GreeterPrompter, LoginError, SecretPromptResult,
+    },
+    crypttab::{CrypttabEntry, cryptsetup_unit},
+    device_spec::{DeviceMatch, DeviceSpec},
+    failed_unlocks::{increment_failed_unlocks, read_failed_unlocks},
+    greeter_protocol::{DeviceStatus, SecretKind, SessionType},
+    luks2::Luks2Header,
+    password_agent::{
+        PasswordRequest, PasswordRequestEvent, is_process_alive, push_password_to_keyring,
+    },
+    sddm_config::SddmConfig,
 };
+use nix::unistd::Pid;
 use smol::lock::Mutex;
 use smol::stream::StreamExt;
 use zeroize::Zeroizing;
 
+const UNLOCK_ONLY_SESSION: &str = "luks-unlock-only.desktop";
+
 pub struct LoginController {
     pub sddm_config: SddmConfig,
     power_client: Option<PowerActionClient>,
-    request_tx: smol::channel::Sender<PasswordRequest>,
+    request_tx: smol::channel::Sender<QueuedRequest>,
+    queued_requests: std::sync::Mutex<HashMap<PathBuf, Option<String>>>, // - ask file path -> prompt message of requests which weren't withdrawn yet
+    greeters: std::sync::Mutex<Vec<smol::channel::Sender<GreeterNotification>>>,
+    device_states: std::sync::Mutex<Vec<DeviceState>>, // - indexed like `sddm_config.luks_devices`
+    unlock_failed_tx: smol::channel::Sender<()>,
+    unlock_failed_rx: smol::channel::Receiver<()>,
+    login_abort_tx: smol::channel::Sender<LoginError>, // - aborts in-flight logins, e.g. when cryptsetup gave up on a device
+    login_abort_rx: smol::channel::Receiver<LoginError>,
+    last_login_error: std::sync::Mutex<Option<LoginError>>,
     login_lock: Mutex<LoginState>,
+    login_throttle: std::sync::Mutex<LoginThrottle>,
+    failed_unlocks: std::sync::Mutex<u32>, // - the persistent failed unlock counter, which is cleared once the login was handed off
 }
 
 struct LoginState {
-    request_rx: smol::channel::Receiver<PasswordRequest>,
-    pending_request: Option<PasswordRequest>,
-    processed_ids: HashSet<String>,
+    request_rx: smol::channel::Receiver<QueuedRequest>,
+    pending_request: Option<QueuedRequest>,
     login_request: Option<LoginRequest>,
 }
 
+#[derive(Default)]
+struct LoginThrottle {
+    failed_logins: u32,
+    next_attempt: Option<Instant>,
+}
+
+struct QueuedRequest {
+    req: PasswordRequest,
+    device_idx: usize,
+    kind: RequestKind,
+}
+
+struct DeviceState {
+    status: DeviceStatus,
+    requester: Option<Pid>,
+    volume: Option<String>, // - the name of the device mapper volume cryptsetup is going to create
+    answered_kind: Option<RequestKind>,
+    failed_attempts: u32,
+    max_tries: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestKind {
+    Passphrase,
+    DevicePassphrase, // - passphrases of devices which don't use the login password
+    TokenPin,         // - FIDO2 / TPM2+PIN / PKCS#11 token PINs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PassphraseCheck {
+    Accepted,
+    Rejected,
+    NoUserKeyslot, // - the device binds keyslots to users, but none to the user logging in
+    BindingUnchecked, // - it couldn't be checked whether the device binds keyslots to users
+    Unchecked,     // - the passphrase couldn't be checked, so leave it up to cryptsetup
+}
+
 pub struct LoginRequest {
     pub user: String,
     pub password: Zeroizing<Box<str>>,
     pub session: PathBuf,
+    pub session_type: SessionType,
 }
 
 impl LoginController {
     pub fn new(sddm_config: SddmConfig, power_client: Option<PowerActionClient>) -> Self {
         let (request_tx, request_rx) = smol::channel::unbounded();
+
+        //Determine how often we can attempt to unlock each device before cryptsetup gives up
+        let crypttab = CrypttabEntry::load().unwrap_or_else(|err| {
+            eprintln!("failed to load crypttab: {err:#}");
+            Vec::new()
+        });
+
+        let device_states = sddm_config
+            .luks_devices
+            .iter()
+            .map(|dev| DeviceState {
+                status: DeviceStatus::Waiting,
+                requester: None,
+                volume: CrypttabEntry::find(&crypttab, dev).map(|e| e.volume.clone()),
+                answered_kind: None,
+                failed_attempts: 0,
+                max_tries: match sddm_config.unlock_tries {
+                    Some(tries) => Some(tries).filter(|&t| t != 0),
+                    None => CrypttabEntry::find(&crypttab, dev).and_then(CrypttabEntry::tries),
+                },
+            })
+            .collect();
+
+        let failed_unlocks =
+            read_failed_unlocks(&sddm_config.efivarfs_root).unwrap_or_else(|err| {
+                eprintln!("failed to read failed unlock counter: {err:#}");
+                0
+            });
+
+        let (unlock_failed_tx, unlock_failed_rx) = smol::channel::bounded(1);
+        let (login_abort_tx, login_abort_rx) = smol::channel::unbounded();
+
         Self {
             sddm_config,
             power_client,
             request_tx,
+            queued_requests: std::sync::Mutex::new(HashMap::new()),
+            greeters: std::sync::Mutex::new(Vec::new()),
+            device_states: std::sync::Mutex::new(device_states),
+            unlock_failed_tx,
+            unlock_failed_rx,
+            login_abort_tx,
+            login_abort_rx,
+            last_login_error: std::sync::Mutex::new(None),
             login_lock: Mutex::new(LoginState {
                 request_rx,
                 pending_request: None,
-                processed_ids: HashSet::new(),
                 login_request: None,
             }),
+            login_throttle: std::sync::Mutex::new(LoginThrottle::default()),
+            failed_unlocks: std::sync::Mutex::new(failed_unlocks),
         }
     }
 
@@ -50,42 +154,848 @@ impl LoginController {
         let pw_reqs = PasswordRequest::listen().expect("failed to listen for password requests");
         smol::pin!(pw_reqs);
 
+        const POLL_INTERVAL: Duration = Duration::from_millis(500);
+        const DEFER_TIMEOUT: Duration = Duration::from_secs(30);
+
         println!("listening for password requests...");
-        while let Some(req) = pw_reqs.next().await {
-            self.process_request(req);
+        let mut deferred_reqs: Vec<(PasswordRequest, Instant)> = Vec::new();
+        loop {
+            //Wait for the next event, periodically waking up to retry deferred requests / check on answered devices
+            let ev = if deferred_reqs.is_empty() && !self.has_answered_devices() {
+                pw_reqs.next().await.map(Some)
+            } else {
+                smol::future::or(async { pw_reqs.next().await.map(Some) }, async {
+                    smol::Timer::after(POLL_INTERVAL).await;
+                    Some(None)
+                })
+                .await
+            };
+
+            match ev {
+                Some(Some(PasswordRequestEvent::Added(req))) => {
+                    if let Some(req) = self.process_request(req) {
+                        println!(
+                            "deferring password request {:?} until its LUKS device can be resolved",
+                            req.ask_path()
+                        );
+                        deferred_reqs.push((req, Instant::now()));
+                    }
+                }
+                Some(Some(PasswordRequestEvent::Withdrawn(path))) => {
+                    deferred_reqs.retain(|(req, _)| req.ask_path() != path);
+                    self.withdraw_request(&path);
+                }
+                Some(None) => {}
+                None => break,
+            }
+
+            //Retry any deferred requests
+            for (req, deferred_at) in std::mem::take(&mut deferred_reqs) {
+                if deferred_at.elapsed() > DEFER_TIMEOUT {
+                    eprintln!(
+                        "giving up on resolving the LUKS device of password request {:?}",
+                        req.ask_path()
+                    );
+                    continue;
+                }
+
+                if let Some(req) = self.process_request(req) {
+                    deferred_reqs.push((req, deferred_at));
+                }
+            }
+
+            //Check if any answered devices were unlocked in the meantime
+            self.poll_answered_devices();
         }
     }
 
-    fn process_request(&self, req: PasswordRequest) {
+    //Returns the request back if it has to be deferred since its device can't be resolved yet
+    fn process_request(&self, req: PasswordRequest) -> Option<PasswordRequest> {
         //Check if we should process this request
+        // - token PIN prompts don't necessarily carry a cryptsetup ID, so determine their device from systemd-cryptsetup's cmdline
+        let is_pin_prompt = req.message.as_deref().is_some_and(is_pin_prompt);
+
         let Some(path) = req
             .id
             .as_ref()
             .and_then(|id| id.strip_prefix("cryptsetup:"))
-            .map(Path::new)
+            .map(DeviceSpec::parse)
+            .or_else(|| {
+                is_pin_prompt
+                    .then(|| Self::cryptsetup_requester_args(&req))
+                    .flatten()
+                    .map(|(_, dev)| dev)
+            })
         else {
             println!("ignoring non-cryptsetup password request: {req:?}");
-            return;
+            return None;
         };
 
-        let canon_path = std::fs::canonicalize(path).unwrap();
+        //Drop requests which are no longer relevant
+        if req.is_expired() {
+            println!("ignoring expired password request for LUKS device {path}");
+            return None;
+        }
+
+        if !req.is_requester_alive() {
+            println!("ignoring password request for LUKS device {path} from dead process");
+            return None;
+        }
+
+        //Match the request against the configured devices
+        let mut unresolved = false;
+        let device_idx =
+            self.sddm_config
+                .luks_devices
+                .iter()
+                .position(|dev| match dev.matches(&path) {
+                    DeviceMatch::Match => true,
+                    DeviceMatch::NoMatch => false,
+                    DeviceMatch::Unresolved => {
+                        unresolved = true;
+                        false
+                    }
+                });
+
+        let Some(device_idx) = device_idx else {
+            if unresolved {
+                // - some device links don't exist yet, so try again later once udev caught up
+                return Some(req);
+            }
+
+            println!("ignoring password request for non-configured LUKS device {path}");
+            return None;
+        };
+
+        //Echoed requests ask for non-secret input; never answer these with the login password
+        // - we claimed the console, so no other agent will answer them either; cancel them instead of leaving them hanging
+        if req.echo {
+            println!("cancelling non-secret password request for LUKS device {path}");
+            if let Err(err) = req.reply(None) {
+                eprintln!("failed to cancel password request: {err:#}");
+            }
+            return None;
+        }
 
-        if !self
+        //Queue the request for processing
+        let device = &self.sddm_config.luks_devices[device_idx];
+
+        // - the same device might be configured using different specs, so compare the resolved devices
+        let mut separate_unresolved = false;
+        let separate_passphrase = self
             .sddm_config
-            .luks_devices
+            .separate_passphrase_devices
             .iter()
-            .any(|dev| std::fs::canonicalize(dev).unwrap() == canon_path)
-        {
-            println!("ignoring password request for non-configured LUKS device {path:?}");
-            return;
+            .any(|dev| match dev.matches(device) {
+                DeviceMatch::Match => true,
+                DeviceMatch::NoMatch => false,
+                DeviceMatch::Unresolved => {
+                    separate_unresolved = true;
+                    false
+                }
+            });
+
+        if !is_pin_prompt && !separate_passphrase && separate_unresolved {
+            // - never answer a device with a separate passphrase using the login password
+            return Some(req);
         }
 
-        //Queue the request for processing
-        println!("queuing password request for LUKS device {path:?}");
+        let kind = if is_pin_prompt {
+            RequestKind::TokenPin
+        } else if separate_passphrase {
+            RequestKind::DevicePassphrase
+        } else {
+            RequestKind::Passphrase
+        };
+
+        // - the user chose to not unlock this device, so cancel the request right away
+        if self.device_status(device_idx) == DeviceStatus::Skipped {
+            println!("cancelling password request for skipped LUKS device {device}");
+            if let Err(err) = req.reply(None) {
+                eprintln!("failed to cancel password request: {err:#}");
+            }
+            return None;
+        }
+
+        println!("queuing {kind:?} password request for LUKS device {device}");
+
+        // - if we already answered a request of the same kind for this device, then a new request means that the password was rejected
+        let rejected = {
+            let mut states = self.device_states.lock().unwrap();
+            let state = &mut states[device_idx];
+            state.requester = req.pid;
+            if let Some((volume, _)) = Self::cryptsetup_requester_args(&req) {
+                state.volume = Some(volume);
+            }
+            let rejected =
+                state.status == DeviceStatus::Answered && state.answered_kind == Some(kind);
+
+            if rejected {
+                state.failed_attempts += 1;
+            }
+            rejected
+        };
+
+        self.set_device_status(
+            device_idx,
+            if rejected {
+                DeviceStatus::Failed
+            } else {
+                DeviceStatus::Prompting
+            },
+        );
+
+        // - forward the prompt message to the greeter, since it might e.g. ask the user to touch their security key
+        let prompt_msg = req.message.as_ref().map(|msg| format!("{msg} ({device})"));
+
+        if let Some(msg) = &prompt_msg {
+            self.notify_greeters(GreeterNotification::Information(msg.clone()));
+        }
+
+        self.queued_requests
+            .lock()
+            .unwrap()
+            .insert(req.ask_path().to_owned(), prompt_msg);
 
         self.request_tx
-            .try_send(req)
+            .try_send(QueuedRequest {
+                req,
+                device_idx,
+                kind,
+            })
             .expect("failed to queue password request");
+
+        None
+    }
+
+    fn cryptsetup_requester_args(req: &PasswordRequest) -> Option<(String, DeviceSpec)> {
+        //systemd-cryptsetup is invoked as `systemd-cryptsetup attach <volume> <device> [<key file>] [<options>]`
+        let cmdline = req.requester_cmdline()?;
+        let (exe, args) = cmdline.split_first()?;
+
+        if !exe.ends_with("systemd-cryptsetup") || args.first()? != "attach" {
+            return None;
+        }
+
+        Some((args.get(1)?.clone(), DeviceSpec::parse(args.get(2)?)))
+    }
+
+    fn device_status(&self, device_idx: usize) -> DeviceStatus {
+        self.device_states.lock().unwrap()[device_idx].status
+    }
+
+    fn set_device_status(&self, device_idx: usize, status: DeviceStatus) {
+        {
+            let mut states = self.device_states.lock().unwrap();
+            if states[device_idx].status == status {
+                return;
+            }
+            states[device_idx].status = status;
+        }
+
+        let device = &self.sddm_config.luks_devices[device_idx];
+        println!("LUKS device {device} is now in state {status:?}");
+
+        self.notify_greeters(self.device_status_notification(device_idx, status));
+    }
+
+    fn device_status_notification(
+        &self,
+        device_idx: usize,
+        status: DeviceStatus,
+    ) -> GreeterNotification {
+        GreeterNotification::DeviceStatus {
+            index: device_idx,
+            count: self.sddm_config.luks_devices.len(),
+            device: self.sddm_config.luks_devices[device_idx].to_string(),
+            status,
+        }
+    }
+
+    fn cancel_request(&self, queued: QueuedRequest) {
+        let device = &self.sddm_config.luks_devices[queued.device_idx];
+        println!("cancelling password request for skipped LUKS device {device}");
+
+        self.queued_requests
+            .lock()
+            .unwrap()
+            .remove(queued.req.ask_path());
+
+        if let Err(err) = queued.req.reply(None) {
+            eprintln!("failed to cancel password request: {err:#}");
+        }
+    }
+
+    fn cancel_skipped_requests(&self, state: &mut LoginState) {
+        let is_skipped =
+            |q: &QueuedRequest| self.device_status(q.device_idx) == DeviceStatus::Skipped;
+
+        if let Some(pending) = state.pending_request.take_if(|q| is_skipped(q)) {
+            self.cancel_request(pending);
+        }
+
+        // - requeue all requests which we don't cancel
+        let mut queued = Vec::new();
+        while let Ok(q) = state.request_rx.try_recv() {
+            queued.push(q);
+        }
+
+        for q in queued {
+            if is_skipped(&q) {
+                self.cancel_request(q);
+            } else {
+                _ = self.request_tx.try_send(q);
+            }
+        }
+    }
+
+    fn unlock_failure_message(&self, device_idx: usize) -> String {
+        let device = &self.sddm_config.luks_devices[device_idx];
+        match self.remaining_attempts(device_idx) {
+            Some(1) => format!("failed to unlock {device} (1 attempt remaining)"),
+            Some(n) => format!("failed to unlock {device} ({n} attempts remaining)"),
+            None => format!("failed to unlock {device}"),
+        }
+    }
+
+    fn remaining_attempts(&self, device_idx: usize) -> Option<u32> {
+        let state = &self.device_states.lock().unwrap()[device_idx];
+        state
+            .max_tries
+            .map(|t| t.saturating_sub(state.failed_attempts))
+    }
+
+    async fn check_passphrase(
+        &self,
+        device_idx: usize,
+        passphrase: &Zeroizing<Box<str>>,
+        user: Option<&str>,
+    ) -> PassphraseCheck {
+        let device = &self.sddm_config.luks_devices[device_idx];
+
+        //Check whether the device is configured to require user-bound keyslots
+        // - the same device might be configured using different specs, so compare the resolved devices; fail closed if we can't
+        let require_binding = user.is_some()
+            && self
+                .sddm_config
+                .user_keyslot_devices
+                .iter()
+                .any(|dev| dev.matches(device) != DeviceMatch::NoMatch);
+
+        let Some(path) = device.resolve() else {
+            eprintln!("can't check passphrase for unresolved device {device}");
+            return if require_binding {
+                PassphraseCheck::BindingUnchecked
+            } else {
+                PassphraseCheck::Unchecked
+            };
+        };
+
+        // - running the KDF takes a while, so do it on a worker thread
+        let verify = self.sddm_config.verify_passphrase;
+        let user = user.map(str::to_owned);
+        let passphrase = passphrase.clone();
+        smol::unblock(move || {
+            check_header_passphrase(
+                &path,
+                passphrase.as_bytes(),
+                user.as_deref(),
+                require_binding,
+                verify,
+            )
+        })
+        .await
+    }
+
+    fn mark_device_answered(&self, device_idx: usize, kind: RequestKind) {
+        self.device_states.lock().unwrap()[device_idx].answered_kind = Some(kind);
+        self.set_device_status(device_idx, DeviceStatus::Answered);
+    }
+
+    fn has_answered_devices(&self) -> bool {
+        self.device_states
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|s| s.status == DeviceStatus::Answered)
+    }
+
+    fn poll_answered_devices(&self) {
+        for device_idx in 0..self.sddm_config.luks_devices.len() {
+            let status = {
+                let states = self.device_states.lock().unwrap();
+                let state = &states[device_idx];
+                if state.status != DeviceStatus::Answered {
+                    continue;
+                }
+
+                //Once the volume shows up the device was unlocked; if cryptsetup exits without it, it gave up on unlocking it
+                let volume_exists = state
+                    .volume
+                    .as_ref()
+                    .is_some_and(|v| Path::new("/dev/mapper").join(v).exists());
+
+                if volume_exists {
+                    DeviceStatus::Unlocked
+                } else if state.requester.is_some_and(|pid| !is_process_alive(pid)) {
+                    if state.volume.is_some() {
+                        DeviceStatus::Failed
+                    } else {
+                        DeviceStatus::Unlocked // - we have no way to tell, so be optimistic
+                    }
+                } else {
+                    continue;
+                }
+            };
+
+            self.set_device_status(device_idx, status);
+
+            if status == DeviceStatus::Failed {
+                let device = &self.sddm_config.luks_devices[device_idx];
+                eprintln!("cryptsetup gave up on unlocking LUKS device {device}");
+
+                let err = LoginError::CryptsetupAborted {
+                    device: device.to_string(),
+                };
+                self.notify_greeters(GreeterNotification::Information(err.to_string()));
+                *self.last_login_error.lock().unwrap() = Some(err.clone());
+                _ = self.login_abort_tx.try_send(err);
+                _ = self.unlock_failed_tx.try_send(());
+            }
+        }
+    }
+
+    pub async fn watch_missing_devices(&self) {
+        let Some(timeout) = self.sddm_config.device_timeout else {
+            return;
+        };
+
+        smol::Timer::after(timeout).await;
+
+        //Notify the greeter about any devices we're still waiting on which don't exist; the user may then choose to skip them
+        // - devices which exist but never prompted are unlocked without a password (e.g. using a TPM2 / key file), so leave these alone
+        // - don't abort in-flight logins, since they might have already answered other devices; they keep waiting for the device to show up
+        for (device_idx, device) in self.sddm_config.luks_devices.iter().enumerate() {
+            if self.device_status(device_idx) != DeviceStatus::Waiting || device.resolve().is_some()
+            {
+                continue;
+            }
+
+            eprintln!("still waiting for LUKS device {device} to become available");
+
+            let err = LoginError::DeviceMissing {
+                device: device.to_string(),
+            };
+            self.set_device_status(device_idx, DeviceStatus::Missing);
+            self.notify_greeters(GreeterNotification::Information(err.to_string()));
+            *self.last_login_error.lock().unwrap() = Some(err);
+        }
+    }
+
+    pub async fn power_off_when_idle(&self, activity: smol::channel::Receiver<()>) {
+        const COUNTDOWN: Duration = Duration::from_secs(30);
+
+        let Some(timeout) = self.sddm_config.idle_timeout else {
+            return;
+        };
+
+        let Some(power_client) = &self.power_client else {
+            eprintln!("can't power off when idle without a systemd manager connection");
+            return;
+        };
+
+        //Returns whether there was no input for the given duration, or `None` if input can no longer be monitored
+        let idle_for = async |duration: Duration| {
+            smol::future::or(async { activity.recv().await.ok().map(|_| false) }, async {
+                smol::Timer::after(duration).await;
+                Some(true)
+            })
+            .await
+        };
+
+        let countdown = COUNTDOWN.min(timeout / 2);
+        loop {
+            match idle_for(timeout - countdown).await {
+                Some(true) => {}
+                Some(false) => continue,
+                None => break,
+            }
+
+            //Give the user a chance to cancel the power off before it happens
+            self.notify_greeters(GreeterNotification::Information(format!(
+                "no input for a while; powering off in {} seconds",
+                countdown.as_secs()
+            )));
+
+            match idle_for(countdown).await {
+                Some(true) => {}
+                Some(false) => {
+                    self.notify_greeters(GreeterNotification::Information(
+                        "power off was cancelled".to_owned(),
+                    ));
+                    continue;
+                }
+                None => break,
+            }
+
+            println!("no input received within the idle timeout; powering off");
+            power_client.perform_action(PowerAction::PowerOff).await;
+            return;
+        }
+
+        eprintln!("input activity can no longer be monitored; disabling the idle timeout");
+    }
+
+    //Resolves once cryptsetup gave up on unlocking a device, at which point no login can succeed anymore
+    pub async fn wait_for_unlock_failure(&self) {
+        _ = self.unlock_failed_rx.recv().await;
+    }
+
+    pub fn last_login_error(&self) -> Option<LoginError> {
+        self.last_login_error.lock().unwrap().clone()
+    }
+
+    fn withdraw_request(&self, ask_path: &Path) {
+        //Forget about the request; it will be dropped once it's dequeued
+        if self
+            .queued_requests
+            .lock()
+            .unwrap()
+            .remove(ask_path)
+            .is_some()
+        {
+            println!("password request {ask_path:?} was withdrawn");
+        }
+    }
+
+    fn is_request_withdrawn(&self, req: &PasswordRequest) -> bool {
+        !self
+            .queued_requests
+            .lock()
+            .unwrap()
+            .contains_key(req.ask_path())
+    }
+
+    fn notify_greeters(&self, notif: GreeterNotification) {
+        // - drop any greeters which disconnected in the meantime
+        self.greeters
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.try_send(notif.clone()).is_ok());
+    }
+
+    async fn try_login(
+        &self,
+        user: &str,
+        password: Zeroizing<Box<str>>,
+        session: &Path,
+        session_type: SessionType,
+        greeter: &GreeterPrompter,
+    ) -> Result<(), LoginError> {
+        //Reject users which aren't allowed to log in before touching any devices
+        if !self.sddm_config.allowed_users.is_empty()
+            && !self.sddm_config.allowed_users.iter().any(|u| u == user)
+        {
+            return Err(LoginError::UserNotAllowed {
+                user: user.to_owned(),
+            });
+        }
+
+        //Make sure the chosen session actually exists, so that the handed off login can start it
+        // - if SDDM wasn't configured with any session directories, there's nothing to check against
+        let has_session_dirs = !self.sddm_config.x11_session_dirs.is_empty()
+            || !self.sddm_config.wayland_session_dirs.is_empty();
+        let session_name = session.file_name().unwrap_or_default();
+
+        if has_session_dirs && !self.sddm_config.has_session(session_name, session_type) {
+            return Err(LoginError::InvalidSession {
+                session: format!("{session_type:?} session {}", session.display()),
+            });
+        }
+
+        // - SDDM's autologin looks up sessions in the Wayland session directories first, so X11 sessions shadowed by a Wayland session can't be handed off
+        let shadowed_session = session_type == SessionType::X11
+            && self
+                .sddm_config
+                .has_session(session_name, SessionType::Wayland);
+        if shadowed_session {
+            greeter
+                .send_message("the selected session can't be started automatically; please log in once the system has started")
+                .await;
+        }
+
+        // - recovery keys aren't bound to any user, and can't be used to log in
+        // - tell the user right away, since the greeter is torn down once the devices are unlocked
+        let recovery_key = is_recovery_key(&password);
+        if recovery_key {
+            println!("login password for user {user:?} is a recovery key");
+            greeter
+                .send_message(
+                    "unlocking using a recovery key; please log in once the system has started",
+                )
+                .await;
+        }
+
+        let mut state = self.login_lock.lock().await;
+
+        // - the login request of logins after shutting down would never be handed off
+        if self.request_tx.is_closed() {
+            return Err(LoginError::ShuttingDown);
+        }
+
+        // - only abort the login for events which happen while it's in progress
+        while self.login_abort_rx.try_recv().is_ok() {}
+
+        loop {
+            //Receive a request to process, or if we already have a request from the last failed login attempt, process that
+            // - the request stays pending until it's answered, so that it's picked up by the next login if this one is cancelled (e.g. because the greeter disconnected)
+            if state.pending_request.is_none() {
+                let next = smol::future::or(async { Ok(state.request_rx.recv().await) }, async {
+                    Err(self
+                        .login_abort_rx
+                        .recv()
+                        .await
+                        .unwrap_or(LoginError::ShuttingDown))
+                })
+                .await;
+
+                match next {
+                    Ok(Ok(r)) => state.pending_request = Some(r),
+                    Ok(Err(_)) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let queued = state.pending_request.as_ref().unwrap();
+
+            //Skip requests which were withdrawn or went stale while they were queued
+            let (req, device_idx, kind) = (&queued.req, queued.device_idx, queued.kind);
+            let device = &self.sddm_config.luks_devices[device_idx];
+
+            if self.is_request_withdrawn(req) {
+                println!("dropping withdrawn password request for {device}");
+                state.pending_request = None;
+                continue;
+            }
+
+            if req.is_expired() {
+                self.queued_requests.lock().unwrap().remove(req.ask_path());
+                state.pending_request = None;
+                return Err(LoginError::TimedOut {
+                    device: device.to_string(),
+                });
+            }
+
+            if req.is_stale() {
+                println!("dropping stale password request for {device}");
+                state.pending_request = None;
+                continue;
+            }
+
+            if self.device_status(device_idx) == DeviceStatus::Skipped {
+                self.cancel_request(state.pending_request.take().unwrap());
+                continue;
+            }
+
+            //Determine the secret to answer the request with
+            let secret = match kind {
+                //Token PINs / device passphrases are entered separately through the greeter; if they're rejected, that doesn't indicate a wrong login password
+                RequestKind::TokenPin | RequestKind::DevicePassphrase => {
+                    if self.device_status(device_idx) == DeviceStatus::Failed {
+                        greeter
+                            .send_message(&self.unlock_failure_message(device_idx))
+                            .await;
+                    }
+
+                    let (secret_kind, prompt) = match kind {
+                        RequestKind::TokenPin => (
+                            SecretKind::Pin,
+                            req.message.clone().unwrap_or_else(|| {
+                                format!("Please enter the token PIN for {device}:")
+                            }),
+                        ),
+                        _ => (
+                            SecretKind::Passphrase,
+                            req.message.clone().unwrap_or_else(|| {
+                                format!("Please enter the passphrase for {device}:")
+                            }),
+                        ),
+                    };
+
+                    match greeter.prompt_secret(secret_kind, &prompt).await {
+                        SecretPromptResult::Answered(secret) => secret,
+                        SecretPromptResult::Unavailable | SecretPromptResult::Failed => {
+                            // - leave the request unanswered, so that it's picked up again by the next login attempt / greeter
+                            return Err(LoginError::SecretPromptUnavailable {
+                                device: device.to_string(),
+                            });
+                        }
+                        SecretPromptResult::Cancelled if kind == RequestKind::TokenPin => {
+                            // - cancelling a PIN prompt might make cryptsetup fall back to a passphrase prompt
+                            println!("token PIN prompt for {device} was cancelled");
+                            self.queued_requests.lock().unwrap().remove(req.ask_path());
+
+                            let queued = state.pending_request.take().unwrap();
+                            if let Err(err) = queued.req.reply(None) {
+                                eprintln!("failed to reply to password request: {err:#}")
+                            }
+                            continue;
+                        }
+                        SecretPromptResult::Cancelled => {
+                            // - the user doesn't want to enter the device passphrase, so skip the device
+                            self.set_device_status(device_idx, DeviceStatus::Skipped);
+                            self.cancel_request(state.pending_request.take().unwrap());
+                            continue;
+                        }
+                    }
+                }
+
+                RequestKind::Passphrase => {
+                    //Check if the device rejected the password we answered with previously; if yes, then the password wasn't correct, so bail
+                    if self.device_status(device_idx) == DeviceStatus::Failed {
+                        eprintln!("got another password request for {device}; login failed");
+
+                        self.set_device_status(device_idx, DeviceStatus::Prompting);
+                        return Err(LoginError::WrongPassword {
+                            device: device.to_string(),
+                            remaining_attempts: self.remaining_attempts(device_idx),
+                        });
+                    }
+
+                    password.clone()
+                }
+            };
+
+            //Check the secret against the LUKS2 header first, so that wrong passwords don't use up any of cryptsetup's tries
+            // - the login password may only unlock keyslots bound to the user logging in
+            let bound_user = (kind == RequestKind::Passphrase && !recovery_key).then_some(user);
+            let check = match kind {
+                RequestKind::TokenPin => PassphraseCheck::Unchecked,
+                _ => self.check_passphrase(device_idx, &secret, bound_user).await,
+            };
+
+            let rejection = match check {
+                PassphraseCheck::Accepted | PassphraseCheck::Unchecked => None,
+                PassphraseCheck::Rejected => Some(LoginError::WrongPassword {
+                    device: device.to_string(),
+                    remaining_attempts: self.remaining_attempts(device_idx),
+                }),
+                PassphraseCheck::NoUserKeyslot => Some(LoginError::NoUserKeyslot {
+                    user: user.to_owned(),
+                    device: device.to_string(),
+                }),
+                PassphraseCheck::BindingUnchecked => Some(LoginError::KeyslotBindingUnchecked {
+                    device: device.to_string(),
+                }),
+            };
+
+            if let Some(err) = rejection {
+                eprintln!("passphrase for {device} was rejected by its LUKS2 header: {err}");
+
+                // - keep the request around for the next login attempt / re-prompt for the device passphrase
+                if kind == RequestKind::Passphrase {
+                    return Err(err);
+                }
+
+                greeter.send_message(&err.to_string()).await;
+                continue;
+            }
+
+            //Answer the request
+            println!("responding to {kind:?} password request for {device}");
+
+            self.queued_requests.lock().unwrap().remove(req.ask_path());
+            let queued = state.pending_request.take().unwrap();
+
+            // - cache the password for AcceptCached consumers (e.g. stage 2 crypttab devices) if configured
+            //   only requesters which accept cached passwords themselves expect their answer to be cached
+            if kind != RequestKind::TokenPin
+                && self.sddm_config.cache_passphrase
+                && queued.req.accept_cached
+                && let Err(err) = push_password_to_keyring("cryptsetup", &secret)
+            {
+                eprintln!("failed to cache password in kernel keyring: {err:#}");
+            }
+
+            if let Err(err) = queued.req.reply(Some(secret)) {
+                eprintln!("failed to reply to password request: {err:#}")
+            }
+
+            self.mark_device_answered(device_idx, kind);
+        }
+
+        //The transmitting end was closed; this means that the unlock was successful / we're shutting down
+        // - the unlock-only session leaves logging in to the stage 2 greeter, so don't hand off the login
+        if recovery_key {
+            println!(
+                "devices were unlocked using a recovery key; not handing off login for user {user:?}"
+            );
+            state.login_request = None;
+        } else if session.file_name() == Some(OsStr::new(UNLOCK_ONLY_SESSION)) {
+            println!("unlock-only session was selected; not handing off login for user {user:?}");
+            state.login_request = None;
+        } else if shadowed_session {
+            println!(
+                "X11 session {} is shadowed by a Wayland session; not handing off login for user {user:?}",
+                session.display()
+            );
+            state.login_request = None;
+        } else {
+            state.login_request = Some(LoginRequest {
+                user: user.to_owned(),
+                password,
+                session: session.to_owned(),
+                session_type,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn record_failed_login(&self, greeter: &GreeterPrompter) {
+        let failed_logins = {
+            let mut throttle = self.login_throttle.lock().unwrap();
+            throttle.failed_logins += 1;
+
+            // - double the delay after each consecutive failed attempt
+            if let Some(base) = self.sddm_config.failure_delay {
+                let delay = base
+                    .saturating_mul(1 << (throttle.failed_logins - 1).min(16))
+                    .min(self.sddm_config.max_failure_delay);
+                throttle.next_attempt = Some(Instant::now() + delay);
+            }
+
+            throttle.failed_logins
+        };
+
+        eprintln!("login attempt failed ({failed_logins} consecutive failures)");
+
+        // - also count the failure persistently, so that the user can be told about it the next time the greeter is shown
+        //   writing to NVRAM may take a while, so do it on a worker thread
+        let efivarfs = self.sddm_config.efivarfs_root.clone();
+        match smol::unblock(move || increment_failed_unlocks(&efivarfs)).await {
+            Ok(Some(count)) => {
+                println!("{count} failed unlock attempts since the last login");
+                *self.failed_unlocks.lock().unwrap() = count;
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("failed to increment failed unlock counter: {err:#}"),
+        }
+
+        //Power off once the user failed to log in too often
+        if let Some(max_failed) = self.sddm_config.power_off_after_failures
+            && failed_logins >= max_failed
+        {
+            eprintln!("too many failed login attempts; powering off");
+            greeter
+                .send_message("too many failed login attempts; powering off")
+                .await;
+
+            match &self.power_client {
+                Some(power_client) => power_client.perform_action(PowerAction::PowerOff).await,
+                None => eprintln!("can't power off without a systemd manager connection"),
+            }
+        }
     }
 
     pub async fn shutdown(&self) -> Option<LoginRequest> {
@@ -100,42 +1010,126 @@ impl GreeterController for LoginController {
         user: &str,
         password: Zeroizing<Box<str>>,
         session: &Path,
-        mut msg_sender: impl FnMut(&str),
-    ) -> bool {
-        let mut state = self.login_lock.lock().await;
+        session_type: SessionType,
+        greeter: &GreeterPrompter,
+    ) -> Result<(), LoginError> {
+        //Make the user wait out the delay from previous failed login attempts
+        let delay = self
+            .login_throttle
+            .lock()
+            .unwrap()
+            .next_attempt
+            .and_then(|t| t.checked_duration_since(Instant::now()));
 
-        //Receive a request to process, or if we already have a request from the last failed login attempt, process that
-        while let Ok(req) = match state.pending_request.take() {
-            Some(r) => Ok(r),
-            None => state.request_rx.recv().await,
-        } {
-            //Check if we processed this request already; if yes, then the password wasn't correct, so bail
-            let id = req.id.as_ref().unwrap();
-            if !state.processed_ids.insert(id.clone()) {
-                eprintln!("got another password request from {id}; login failed");
-                msg_sender(&format!("failed to unlock {id}"));
+        if let Some(delay) = delay {
+            greeter
+                .send_message(&format!(
+                    "too many failed login attempts; please wait {} seconds",
+                    delay.as_secs().max(1)
+                ))
+                .await;
+            smol::Timer::after(delay).await;
+        }
 
-                state.processed_ids.clear();
-                state.pending_request = Some(req);
-                return false;
+        let res = self
+            .try_login(user, password, session, session_type, greeter)
+            .await;
+        match &res {
+            Ok(()) => *self.login_throttle.lock().unwrap() = LoginThrottle::default(),
+            Err(err) => {
+                // - only throttle failures caused by wrong credentials
+                if err.is_auth_failure() {
+                    self.record_failed_login(greeter).await;
+                }
+                *self.last_login_error.lock().unwrap() = Some(err.clone());
             }
+        }
+        res
+    }
 
-            //Answer the request
-            println!("responding to password request from {id}");
+    async fn skip_device(&self, device: &str) {
+        let Some(device_idx) = self
+            .sddm_config
+            .luks_devices
+            .iter()
+            .position(|d| d.to_string() == device)
+        else {
+            eprintln!("greeter attempted to skip unknown LUKS device {device:?}");
+            return;
+        };
 
-            if let Err(err) = req.reply(Some(password.clone())) {
-                eprintln!("failed to reply to password request: {err:#}")
+        let status = self.device_status(device_idx);
+        match status {
+            DeviceStatus::Waiting | DeviceStatus::Missing => {}
+            DeviceStatus::Prompting | DeviceStatus::Failed => {
+                //Cancel the pending password request; if we're currently logging in, the login will take care of it
+                println!("skipping LUKS device {device}");
+                self.set_device_status(device_idx, DeviceStatus::Skipped);
+
+                if let Some(mut state) = self.login_lock.try_lock() {
+                    self.cancel_skipped_requests(&mut state);
+                }
+                return;
+            }
+            _ => {
+                eprintln!("can't skip LUKS device {device} in state {status:?}");
+                return;
             }
         }
 
-        //The transmitting end was closed; this means that the unlock was successful / we're shutting down
-        state.login_request = Some(LoginRequest {
-            user: user.to_owned(),
-            password,
-            session: session.to_owned(),
-        });
+        //Stop the cryptsetup unit, which cancels its pending start job waiting for the device
+        let volume = self.device_states.lock().unwrap()[device_idx]
+            .volume
+            .clone();
+        let Some(volume) = volume else {
+            eprintln!("can't skip LUKS device {device} since its volume name is unknown");
+            return;
+        };
+
+        let Some(power_client) = &self.power_client else {
+            eprintln!("can't skip LUKS device {device} without a systemd manager connection");
+            return;
+        };
+
+        println!("skipping LUKS device {device}");
+        if let Err(err) = power_client.stop_unit(&cryptsetup_unit(&volume)).await {
+            eprintln!("failed to skip LUKS device {device}: {err:#}");
+            return;
+        }
+
+        self.set_device_status(device_idx, DeviceStatus::Skipped);
+    }
+
+    fn subscribe_notifications(&self) -> smol::channel::Receiver<GreeterNotification> {
+        let (tx, rx) = smol::channel::unbounded();
+
+        //Replay the status of all devices, and the prompt messages of all requests which are still queued
+        for device_idx in 0..self.sddm_config.luks_devices.len() {
+            let status = self.device_status(device_idx);
+            _ = tx.try_send(self.device_status_notification(device_idx, status));
+        }
+
+        for msg in self.queued_requests.lock().unwrap().values().flatten() {
+            _ = tx.try_send(GreeterNotification::Information(msg.clone()));
+        }
 
-        true
+        // - tell the user about failed unlock attempts since they last logged in, e.g. by someone else trying to guess their password
+        match *self.failed_unlocks.lock().unwrap() {
+            0 => {}
+            1 => {
+                _ = tx.try_send(GreeterNotification::Information(
+                    "1 failed unlock attempt since the last login".to_owned(),
+                ));
+            }
+            n => {
+                _ = tx.try_send(GreeterNotification::Information(format!(
+                    "{n} failed unlock attempts since the last login"
+                )));
+            }
+        }
+
+        self.greeters.lock().unwrap().push(tx);
+        rx
     }
 
     fn can_perform_power_action(&self, act: PowerAction) -> bool {
@@ -154,3 +1148,147 @@ impl GreeterController for LoginController {
             .await
     }
 }
+
+//Token PIN prompts of systemd-cryptsetup / libcryptsetup token plugins
+// - only match the start of the prompt, since the rest of it may contain the (arbitrary) volume name
+fn is_pin_prompt(message: &str) -> bool {
+    const PIN_PROMPTS: &[&str] = &[
+        "security token PIN",     // - FIDO2
+        "TPM2 PIN",               // - TPM2+PIN
+        "LUKS2 token PIN",        // - libcryptsetup token plugins
+        "PIN for security token", // - PKCS#11
+    ];
+
+    let Some(prompt) = message.strip_prefix("Please enter ") else {
+        return false;
+    };
+    let prompt = prompt.strip_prefix("correct ").unwrap_or(prompt);
+
+    PIN_PROMPTS.iter().any(|p| prompt.starts_with(p))
+}
+
+//Recovery keys generated by `systemd-cryptenroll --recovery-key` consist of 8 dash-separated blocks of 8 modhex characters
+fn is_recovery_key(password: &str) -> bool {
+    const MODHEX: &str = "cbdefghijklnrtuv";
+
+    let blocks: Vec<_> = password.trim().split('-').collect();
+    blocks.len() == 8
+        && blocks
+            .iter()
+            .all(|b| b.len() == 8 && b.chars().all(|c| MODHEX.contains(c)))
+}
+
+//Checks a passphrase against the LUKS2 header of the device at the given path
+// - only fail closed if the device is known to bind keyslots to users; otherwise leave any checks we can't perform up to cryptsetup
+fn check_header_passphrase(
+    path: &Path,
+    passphrase: &[u8],
+    user: Option<&str>,
+    require_binding: bool,
+    verify: bool,
+) -> PassphraseCheck {
+    let failed_check = if require_binding {
+        PassphraseCheck::BindingUnchecked
+    } else {
+        PassphraseCheck::Unchecked
+    };
+
+    // - LUKS1 devices can't bind keyslots to users
+    let hdr = match Luks2Header::read(path) {
+        Ok(Some(hdr)) => hdr,
+        Ok(None) => return failed_check,
+        Err(err) => {
+            eprintln!("failed to read the LUKS2 header of {path:?}: {err:#}");
+            return failed_check;
+        }
+    };
+
+    //If the device binds keyslots to users, only accept passphrases for the user's own keyslots
+    if let Some(user) = user
+        && (require_binding || hdr.has_user_keyslots())
+    {
+        let keyslots = hdr.user_keyslots(user);
+        if keyslots.is_empty() {
+            return PassphraseCheck::NoUserKeyslot;
+        }
+
+        // - if we can't check the keyslots we can't enforce the binding, so fail closed
+        return match hdr.verify_passphrase(path, passphrase, Some(&keyslots)) {
+            Ok(Some(_)) => PassphraseCheck::Accepted,
+            Ok(None) => PassphraseCheck::Rejected,
+            Err(err) => {
+                eprintln!("failed to check user-bound LUKS2 keyslots of {path:?}: {err:#}");
+                PassphraseCheck::BindingUnchecked
+            }
+        };
+    }
+
+    if !verify {
+        return PassphraseCheck::Unchecked;
+    }
+
+    match hdr.verify_passphrase(path, passphrase, None) {
+        Ok(Some(_)) => PassphraseCheck::Accepted,
+        Ok(None) => PassphraseCheck::Rejected,
+        Err(err) => {
+            eprintln!("failed to check passphrase against the LUKS2 header of {path:?}: {err:#}");
+            PassphraseCheck::Unchecked
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> &'static Path {
+        Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/luks2-header.img"
+        ))
+    }
+
+    #[test]
+    fn unreadable_header() {
+        //Devices whose header we can't read are left up to cryptsetup, unless they are configured to require user-bound keyslots
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/missing.img"));
+        let not_luks = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+
+        for path in [path, not_luks] {
+            assert_eq!(
+                check_header_passphrase(path, b"hunter2", Some("alice"), false, true),
+                PassphraseCheck::Unchecked
+            );
+            assert_eq!(
+                check_header_passphrase(path, b"hunter2", Some("alice"), true, true),
+                PassphraseCheck::BindingUnchecked
+            );
+        }
+    }
+
+    #[test]
+    fn user_bound_keyslots() {
+        let path = fixture();
+
+        assert_eq!(
+            check_header_passphrase(path, b"battery staple", Some("alice"), false, false),
+            PassphraseCheck::Accepted
+        );
+        assert_eq!(
+            check_header_passphrase(path, b"correct horse", Some("alice"), false, false),
+            PassphraseCheck::Rejected
+        );
+        assert_eq!(
+            check_header_passphrase(path, b"correct horse", Some("bob"), false, false),
+            PassphraseCheck::NoUserKeyslot
+        );
+        assert_eq!(
+            check_header_passphrase(path, b"correct horse", None, false, false),
+            PassphraseCheck::Unchecked
+        );
+        assert_eq!(
+            check_header_passphrase(path, b"correct horse", None, false, true),
+            PassphraseCheck::Accepted
+        );
+    }
+}
@@ -0,0 +1,556 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit, generic_array::GenericArray};
+use anyhow::{Context, Result, bail, ensure};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Deserializer};
+use sha2::Digest;
+use zeroize::Zeroizing;
+
+use crate::argon2_lanes::{Variant, argon2};
+
+const LUKS2_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const LUKS2_BINARY_HEADER_SIZE: u64 = 4096;
+const SECTOR_SIZE: usize = 512;
+const USER_TOKEN_TYPE: &str = "luks-stage1-sddm-user";
+
+//A minimal LUKS2 header parser, just enough to check passphrases against a device's keyslots
+pub struct Luks2Header {
+    metadata: Luks2Metadata,
+}
+
+#[derive(Deserialize)]
+struct Luks2Metadata {
+    keyslots: HashMap<String, KeyslotEntry>,
+    digests: HashMap<String, KeyDigest>,
+    #[serde(default)]
+    tokens: HashMap<String, Token>,
+}
+
+//Keyslots we can't parse (e.g. reencryption keyslots) mustn't make parsing the entire header fail
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyslotEntry {
+    Supported(Keyslot),
+    Unsupported {
+        #[serde(rename = "type")]
+        kind: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct Keyslot {
+    #[serde(rename = "type")]
+    kind: String,
+    key_size: usize,
+    priority: Option<u32>,
+    af: AntiForensicSplitter,
+    area: KeyslotArea,
+    kdf: Kdf,
+}
+
+#[derive(Deserialize)]
+struct AntiForensicSplitter {
+    #[serde(rename = "type")]
+    kind: String,
+    stripes: usize,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct KeyslotArea {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(deserialize_with = "deserialize_u64_str")]
+    offset: u64,
+    encryption: String,
+    key_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum Kdf {
+    #[serde(rename = "pbkdf2")]
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: String,
+    },
+    #[serde(rename = "argon2i")]
+    Argon2i {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    #[serde(rename = "argon2id")]
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct Token {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    keyslots: Vec<String>,
+    user: Option<String>, // - only present for user binding tokens
+}
+
+#[derive(Deserialize)]
+struct KeyDigest {
+    #[serde(rename = "type")]
+    kind: String,
+    keyslots: Vec<String>,
+    hash: String,
+    iterations: u32,
+    salt: String,
+    digest: String,
+}
+
+impl Luks2Header {
+    //Returns `None` for LUKS1 devices
+    pub fn read(device: &Path) -> Result<Option<Luks2Header>> {
+        let mut dev = std::fs::File::open(device).context("failed to open LUKS device")?;
+
+        //Read the binary header
+        let mut hdr = [0u8; LUKS2_BINARY_HEADER_SIZE as usize];
+        dev.read_exact(&mut hdr)
+            .context("failed to read LUKS binary header")?;
+
+        ensure!(hdr.starts_with(LUKS2_MAGIC), "not a LUKS device");
+
+        let version = u16::from_be_bytes([hdr[6], hdr[7]]);
+        if version == 1 {
+            return Ok(None);
+        }
+        ensure!(version == 2, "unsupported LUKS version {version}");
+
+        let hdr_size = u64::from_be_bytes(hdr[8..16].try_into().unwrap());
+        ensure!(
+            hdr_size > LUKS2_BINARY_HEADER_SIZE && hdr_size <= 4 * 1024 * 1024,
+            "malformed LUKS2 header size {hdr_size}"
+        );
+
+        //Read the JSON metadata area following the binary header
+        let mut json = vec![0u8; (hdr_size - LUKS2_BINARY_HEADER_SIZE) as usize];
+        dev.read_exact(&mut json)
+            .context("failed to read LUKS2 JSON metadata")?;
+
+        let json_len = json.iter().position(|&b| b == 0).unwrap_or(json.len());
+        let metadata = serde_json::from_slice(&json[..json_len])
+            .context("failed to parse LUKS2 JSON metadata")?;
+
+        Ok(Some(Luks2Header { metadata }))
+    }
+
+    //Keyslots can be bound to users through tokens like `{"type": "luks-stage1-sddm-user", "keyslots": ["1"], "user": "alice"}`
+    pub fn has_user_keyslots(&self) -> bool {
+        self.user_tokens().next().is_some()
+    }
+
+    pub fn user_keyslots(&self, user: &str) -> Vec<String> {
+        self.user_tokens()
+            .filter(|t| t.user.as_deref() == Some(user))
+            .flat_map(|t| t.keyslots.iter().cloned())
+            .collect()
+    }
+
+    fn user_tokens(&self) -> impl Iterator<Item = &Token> {
+        self.metadata
+            .tokens
+            .values()
+            .filter(|t| t.kind == USER_TOKEN_TYPE)
+    }
+
+    //Returns the ID of the keyslot the passphrase unlocks, if any; if `keyslots` is given, only those keyslots are tried
+    // - fails if no keyslot matched, but some keyslots couldn't be checked, since the passphrase might unlock one of those
+    pub fn verify_passphrase(
+        &self,
+        device: &Path,
+        passphrase: &[u8],
+        keyslots: Option<&[String]>,
+    ) -> Result<Option<String>> {
+        let mut dev = std::fs::File::open(device).context("failed to open LUKS device")?;
+
+        let mut keyslot_ids: Vec<_> = self
+            .metadata
+            .keyslots
+            .keys()
+            .filter(|id| keyslots.is_none_or(|k| k.contains(id)))
+            .collect();
+        keyslot_ids.sort_by_key(|id| id.parse::<u32>().unwrap_or(u32::MAX));
+
+        let mut unchecked = false;
+        for id in keyslot_ids {
+            let keyslot = match &self.metadata.keyslots[id] {
+                KeyslotEntry::Supported(keyslot) => keyslot,
+                // - only luks2 keyslots hold passphrase-protected volume keys
+                KeyslotEntry::Unsupported { kind } if kind != "luks2" => continue,
+                KeyslotEntry::Unsupported { .. } => {
+                    eprintln!("unsupported LUKS2 keyslot {id}");
+                    unchecked = true;
+                    continue;
+                }
+            };
+
+            // - a priority of 0 means that the keyslot is ignored unless explicitly requested
+            if keyslot.priority == Some(0) && keyslots.is_none() {
+                continue;
+            }
+
+            match self.try_keyslot(&mut dev, id, keyslot, passphrase) {
+                Ok(true) => return Ok(Some(id.clone())),
+                Ok(false) => {}
+                Err(err) => {
+                    eprintln!("failed to check LUKS2 keyslot {id}: {err:#}");
+                    unchecked = true;
+                }
+            }
+        }
+
+        ensure!(!unchecked, "some LUKS2 keyslots couldn't be checked");
+        Ok(None)
+    }
+
+    fn try_keyslot(
+        &self,
+        dev: &mut std::fs::File,
+        id: &str,
+        keyslot: &Keyslot,
+        passphrase: &[u8],
+    ) -> Result<bool> {
+        ensure!(
+            keyslot.kind == "luks2",
+            "unsupported keyslot type {:?}",
+            keyslot.kind
+        );
+        ensure!(
+            keyslot.af.kind == "luks1",
+            "unsupported AF type {:?}",
+            keyslot.af.kind
+        );
+        ensure!(
+            keyslot.area.kind == "raw",
+            "unsupported keyslot area type {:?}",
+            keyslot.area.kind
+        );
+
+        let digest = self
+            .metadata
+            .digests
+            .values()
+            .find(|d| d.keyslots.iter().any(|k| k == id))
+            .context("no digest for keyslot")?;
+
+        //Derive the keyslot area key from the passphrase
+        let mut area_key = Zeroizing::new(vec![0u8; keyslot.area.key_size]);
+        match &keyslot.kdf {
+            Kdf::Pbkdf2 {
+                hash,
+                iterations,
+                salt,
+            } => pbkdf2(
+                hash,
+                passphrase,
+                &decode_b64(salt)?,
+                *iterations,
+                &mut area_key,
+            )?,
+            Kdf::Argon2i {
+                time,
+                memory,
+                cpus,
+                salt,
+            } => argon2(
+                Variant::Argon2i,
+                *time,
+                *memory,
+                *cpus,
+                passphrase,
+                &decode_b64(salt)?,
+                &mut area_key,
+            )?,
+            Kdf::Argon2id {
+                time,
+                memory,
+                cpus,
+                salt,
+            } => argon2(
+                Variant::Argon2id,
+                *time,
+                *memory,
+                *cpus,
+                passphrase,
+                &decode_b64(salt)?,
+                &mut area_key,
+            )?,
+        }
+
+        //Read and decrypt the keyslot's key material
+        let split_len = keyslot.key_size * keyslot.af.stripes;
+        let mut material = Zeroizing::new(vec![0u8; split_len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE]);
+
+        dev.seek(SeekFrom::Start(keyslot.area.offset))?;
+        dev.read_exact(&mut material)
+            .context("failed to read keyslot area")?;
+
+        match (keyslot.area.encryption.as_str(), area_key.len()) {
+            ("aes-xts-plain64", 32) => xts_decrypt::<aes::Aes128>(&area_key, &mut material),
+            ("aes-xts-plain64", 64) => xts_decrypt::<aes::Aes256>(&area_key, &mut material),
+            (enc, _) => bail!("unsupported keyslot encryption {enc:?}"),
+        }
+
+        //Merge the anti-forensic stripes to obtain the volume key, then check it against the digest
+        let volume_key = af_merge(
+            &material[..split_len],
+            keyslot.key_size,
+            keyslot.af.stripes,
+            &keyslot.af.hash,
+        )?;
+
+        ensure!(
+            digest.kind == "pbkdf2",
+            "unsupported digest type {:?}",
+            digest.kind
+        );
+
+        let expected = decode_b64(&digest.digest)?;
+        let mut actual = vec![0u8; expected.len()];
+        pbkdf2(
+            &digest.hash,
+            &volume_key,
+            &decode_b64(&digest.salt)?,
+            digest.iterations,
+            &mut actual,
+        )?;
+
+        Ok(actual == expected)
+    }
+}
+
+fn deserialize_u64_str<'de, D: Deserializer<'de>>(de: D) -> Result<u64, D::Error> {
+    // - LUKS2 stores 64 bit values as strings, since JSON numbers can't represent them
+    String::deserialize(de)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn decode_b64(val: &str) -> Result<Vec<u8>> {
+    BASE64_STANDARD
+        .decode(val)
+        .context("malformed base64 value")
+}
+
+fn pbkdf2(hash: &str, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) -> Result<()> {
+    match hash {
+        "sha1" => pbkdf2::pbkdf2_hmac::<sha1::Sha1>(password, salt, iterations, out),
+        "sha256" => pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password, salt, iterations, out),
+        "sha512" => pbkdf2::pbkdf2_hmac::<sha2::Sha512>(password, salt, iterations, out),
+        _ => bail!("unsupported PBKDF2 hash {hash:?}"),
+    }
+    Ok(())
+}
+
+fn xts_decrypt<C: BlockEncrypt + BlockDecrypt + KeyInit>(key: &[u8], data: &mut [u8]) {
+    let (data_key, tweak_key) = key.split_at(key.len() / 2);
+    let data_cipher = C::new_from_slice(data_key).unwrap();
+    let tweak_cipher = C::new_from_slice(tweak_key).unwrap();
+
+    for (sector_idx, sector) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+        // - plain64 IVs are the little-endian sector number
+        let mut tweak = GenericArray::default();
+        tweak[..8].copy_from_slice(&(sector_idx as u64).to_le_bytes());
+        tweak_cipher.encrypt_block(&mut tweak);
+
+        for block in sector.chunks_mut(16) {
+            let block = GenericArray::from_mut_slice(block);
+            block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+            data_cipher.decrypt_block(block);
+            block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+
+            //Multiply the tweak by alpha in GF(2^128)
+            let mut carry = 0;
+            for b in tweak.iter_mut() {
+                let next_carry = *b >> 7;
+                *b = (*b << 1) | carry;
+                carry = next_carry;
+            }
+            if carry != 0 {
+                tweak[0] ^= 0x87;
+            }
+        }
+    }
+}
+
+fn af_merge(
+    material: &[u8],
+    key_size: usize,
+    stripes: usize,
+    hash: &str,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let diffuse = match hash {
+        "sha1" => diffuse::<sha1::Sha1>,
+        "sha256" => diffuse::<sha2::Sha256>,
+        "sha512" => diffuse::<sha2::Sha512>,
+        _ => bail!("unsupported AF hash {hash:?}"),
+    };
+
+    let mut key = Zeroizing::new(vec![0u8; key_size]);
+    for (i, stripe) in material.chunks(key_size).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        if i + 1 < stripes {
+            diffuse(&mut key);
+        }
+    }
+
+    Ok(key)
+}
+
+fn diffuse<H: Digest>(buf: &mut [u8]) {
+    let digest_size = <H as Digest>::output_size();
+    for (i, chunk) in buf.chunks_mut(digest_size).enumerate() {
+        let mut hasher = H::new();
+        hasher.update((i as u32).to_be_bytes());
+        hasher.update(&*chunk);
+
+        let hash = hasher.finalize();
+        chunk.copy_from_slice(&hash[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Generated by testdata/gen-luks2-header.py; keyslot 0 uses PBKDF2, keyslot 1 uses Argon2id and is bound to "alice"
+    fn fixture() -> (&'static Path, Luks2Header) {
+        let path = Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/luks2-header.img"
+        ));
+        (path, Luks2Header::read(path).unwrap().unwrap())
+    }
+
+    #[test]
+    fn pbkdf2_keyslot() {
+        let (path, hdr) = fixture();
+        let keyslot = ["0".to_string()];
+
+        assert_eq!(
+            hdr.verify_passphrase(path, b"correct horse", Some(&keyslot))
+                .unwrap(),
+            Some("0".to_string())
+        );
+        assert_eq!(
+            hdr.verify_passphrase(path, b"correct horsf", Some(&keyslot))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn argon2id_keyslot() {
+        let (path, hdr) = fixture();
+        let keyslot = ["1".to_string()];
+
+        assert_eq!(
+            hdr.verify_passphrase(path, b"battery staple", Some(&keyslot))
+                .unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(
+            hdr.verify_passphrase(path, b"correct horse", Some(&keyslot))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn any_keyslot() {
+        let (path, hdr) = fixture();
+
+        assert_eq!(
+            hdr.verify_passphrase(path, b"correct horse", None).unwrap(),
+            Some("0".to_string())
+        );
+        assert_eq!(
+            hdr.verify_passphrase(path, b"battery staple", None)
+                .unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(hdr.verify_passphrase(path, b"", None).unwrap(), None);
+    }
+
+    #[test]
+    fn user_keyslot_tokens() {
+        let (_, hdr) = fixture();
+
+        assert!(hdr.has_user_keyslots());
+        assert_eq!(hdr.user_keyslots("alice"), ["1"]);
+        assert!(hdr.user_keyslots("bob").is_empty());
+    }
+
+    #[test]
+    fn xts_known_answer() {
+        //IEEE P1619 XTS-AES-128 test vector 1
+        let mut data = [
+            0x91, 0x7c, 0xf6, 0x9e, 0xbd, 0x68, 0xb2, 0xec, 0x9b, 0x9f, 0xe9, 0xa3, 0xea, 0xdd,
+            0xa6, 0x92, 0xcd, 0x43, 0xd2, 0xf5, 0x95, 0x98, 0xed, 0x85, 0x8c, 0x02, 0xc2, 0x65,
+            0x2f, 0xbf, 0x92, 0x2e,
+        ];
+        xts_decrypt::<aes::Aes128>(&[0; 32], &mut data);
+        assert_eq!(data, [0; 32]);
+    }
+
+    #[test]
+    fn unsupported_keyslots_are_tolerated() {
+        let metadata: Luks2Metadata = serde_json::from_str(
+            r#"{
+                "keyslots": {
+                    "0": {
+                        "type": "luks2",
+                        "key_size": 64,
+                        "af": {"type": "luks1", "stripes": 4000, "hash": "sha256"},
+                        "area": {"type": "raw", "offset": "32768", "size": "258048", "encryption": "aes-xts-plain64", "key_size": 64},
+                        "kdf": {"type": "pbkdf2", "hash": "sha256", "iterations": 1000, "salt": ""}
+                    },
+                    "1": {
+                        "type": "reencrypt",
+                        "key_size": 1,
+                        "area": {"type": "none", "offset": "290816", "size": "4096"},
+                        "mode": "reencrypt",
+                        "direction": "forward"
+                    },
+                    "2": {
+                        "type": "luks2",
+                        "key_size": 64,
+                        "af": {"type": "luks1", "stripes": 4000, "hash": "sha256"},
+                        "area": {"type": "raw", "offset": "294912", "size": "258048", "encryption": "aes-xts-plain64", "key_size": 64},
+                        "kdf": {"type": "scrypt", "salt": ""}
+                    }
+                },
+                "digests": {},
+                "segments": {},
+                "config": {}
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(metadata.keyslots["0"], KeyslotEntry::Supported(_)));
+        assert!(
+            matches!(&metadata.keyslots["1"], KeyslotEntry::Unsupported { kind } if kind == "reencrypt")
+        );
+        assert!(
+            matches!(&metadata.keyslots["2"], KeyslotEntry::Unsupported { kind } if kind == "luks2")
+        );
+    }
+}
@@ -1,19 +1,34 @@
-use std::{os::fd::AsRawFd, path::Path, process::ExitCode, sync::Arc};
+use std::{
+    os::{fd::AsRawFd, unix::fs::DirBuilderExt},
+    path::Path,
+    process::ExitCode,
+    sync::Arc,
+};
 
+mod argon2_lanes;
 mod control_server;
+mod crypttab;
+mod device_spec;
+mod failed_unlocks;
 mod failsafe;
+mod file_flags;
+mod greeter_protocol;
+mod greeter_supervisor;
+mod idle_watchdog;
 mod login_controller;
+mod luks2;
 mod password_agent;
 mod power_actions;
 mod sddm_config;
 
 use crate::{
     control_server::greeter_control_server,
+    greeter_supervisor::GreeterSupervisor,
     login_controller::LoginController,
     power_actions::PowerActionClient,
     sddm_config::{SddmConfig, write_transient_sddm_config},
 };
-use smol::{process::Command, stream::StreamExt};
+use smol::{net::unix::UnixListener, stream::StreamExt};
 
 fn main() -> ExitCode {
     //Parse the SDDM config file we're given
@@ -82,14 +97,36 @@ fn main() -> ExitCode {
             }
         });
 
-        //Start an SDDM control server with an associated Unix socket
-        let socket_path =
+        // - keep an eye on devices which don't show up
+        let device_watchdog = smol::spawn({
+            let controller = controller.clone();
+            async move {
+                controller.watch_missing_devices().await;
+            }
+        });
+
+        // - power off if nobody is around to unlock the devices
+        let idle_watchdog = smol::spawn({
+            let controller = controller.clone();
+            async move {
+                controller
+                    .power_off_when_idle(idle_watchdog::watch_input_activity())
+                    .await;
+            }
+        });
+
+        //Bind the SDDM control socket in a private directory, so that nobody else can connect to it
+        let socket_dir =
             std::env::temp_dir().join(format!("stage1-sddm-greeter-{}", std::process::id()));
 
-        let control_server = smol::spawn(greeter_control_server(
-            socket_path.clone(),
-            controller.clone(),
-        ));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&socket_dir)
+            .expect("failed to create greeter control socket directory");
+
+        let socket_path = socket_dir.join("control.sock");
+        let control_socket =
+            UnixListener::bind(&socket_path).expect("failed to bind greeter control socket");
 
         //Wait for a DRI/DRM device to become available
         if !wait_for_dri_device()
@@ -109,23 +146,18 @@ fn main() -> ExitCode {
             }
         };
 
-        //Start the SDDM greeter
-        let mut greeter = {
-            let mut cmd = Command::new(&controller.sddm_config.greeter);
-            let cmd = cmd
-                .arg("--socket")
-                .arg(&socket_path)
-                .env("SDDM_CONFIG", sddm_config_path);
-
-            let cmd = if let Some(theme) = &controller.sddm_config.theme {
-                // - if we have a theme configured, pass that to the greeter
-                cmd.arg("--theme").arg(theme)
-            } else {
-                cmd
-            };
+        //Start the SDDM greeter, and restart it if it crashes
+        let mut greeter =
+            GreeterSupervisor::spawn(&controller.sddm_config, sddm_config_path, &socket_path)
+                .expect("failed to start SDDM greeter");
 
-            cmd.spawn().expect("failed to start SDDM greeter")
-        };
+        //Start an SDDM control server, which only accepts connections from the greeter
+        // - the controller's state is kept across greeter restarts, so the new greeter picks up where the old one left off
+        let control_server = smol::spawn(greeter_control_server(
+            control_socket,
+            greeter.pid(),
+            controller.clone(),
+        ));
 
         //Wait until we receive a SIGTERM / SIGINT signal, or the greeter finishes
         let mut signals =
@@ -136,6 +168,7 @@ fn main() -> ExitCode {
             .expect("failed to send sd-notify ready notification");
 
         let mut failsafe_engaged = false;
+        let mut unlock_failed = false;
         smol::future::or(
             smol::future::or(
                 async {
@@ -145,24 +178,37 @@ fn main() -> ExitCode {
                         .unwrap()
                         .expect("failed to wait for a terminating signal");
                 },
-                async {
-                    failsafe_signal.await;
-                    failsafe_engaged = true;
-                },
+                smol::future::or(
+                    async {
+                        failsafe_signal.await;
+                        failsafe_engaged = true;
+                    },
+                    async {
+                        // - fall back to the console once cryptsetup gave up on a device
+                        controller.wait_for_unlock_failure().await;
+                        unlock_failed = true;
+                    },
+                ),
             ),
             async {
-                greeter
-                    .status()
-                    .await
-                    .expect("failed to wait for SDDM greeter");
+                greeter.supervise().await;
             },
         )
         .await;
 
+        if unlock_failed {
+            eprintln!("unlocking a LUKS device failed - falling back to the console");
+        }
+
         //Shutdown password request handling
         pw_req_handler.cancel().await;
+        device_watchdog.cancel().await;
+        idle_watchdog.cancel().await;
 
-        if !failsafe_engaged && let Some(request) = controller.shutdown().await {
+        if !failsafe_engaged
+            && !unlock_failed
+            && let Some(request) = controller.shutdown().await
+        {
             //We got a pending login request before shutting down; prepare for a handoff to the proper SDDM service
             if sysroot_pivot_task.is_finished() {
                 write_transient_sddm_config(&request)
@@ -177,17 +223,17 @@ fn main() -> ExitCode {
         //Shutdown the greeter control server; this will make the greeter shutdown as well
         control_server.cancel().await;
 
-        //Retrieve the greeter status, unless the failsafe was engaged, then kill it
-        if failsafe_engaged {
-            _ = greeter.kill();
+        //Retrieve the greeter status, unless the failsafe was engaged / unlocking failed, then kill it
+        if failsafe_engaged || unlock_failed {
+            greeter.kill();
         }
 
-        let greeter_status = greeter
-            .status()
-            .await
-            .expect("failed to wait for SDDM greeter");
+        let greeter_status = greeter.status().await;
 
-        if greeter_status.success() {
+        if unlock_failed && let Some(err) = controller.last_login_error() {
+            eprintln!("unlocking failed: {err}");
+            ExitCode::from(err.exit_code())
+        } else if greeter_status.success() {
             ExitCode::SUCCESS
         } else {
             eprintln!("greeter exited with status {greeter_status}");
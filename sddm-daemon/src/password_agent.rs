@@ -4,10 +4,17 @@ use std::{
     io::ErrorKind,
     os::{fd::AsFd, unix::net::UnixDatagram},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{Context, Result};
-use inotify::{Inotify, WatchMask};
+use inotify::{EventMask, Inotify, WatchMask};
+use nix::{
+    errno::Errno,
+    sys::signal::kill,
+    time::{ClockId, clock_gettime},
+    unistd::Pid,
+};
 use smol::{
     Async,
     stream::{Stream, StreamExt},
@@ -16,20 +23,37 @@ use zeroize::Zeroizing;
 
 #[derive(Debug, Hash)]
 pub struct PasswordRequest {
+    ask_path: PathBuf,
     socket_path: PathBuf,
     pub id: Option<String>,
     pub message: Option<String>,
+    pub pid: Option<Pid>,
+    pub not_after: Option<Duration>, // - in terms of CLOCK_MONOTONIC
+    pub accept_cached: bool,
+    pub echo: bool,
+}
+
+#[derive(Debug)]
+pub enum PasswordRequestEvent {
+    Added(PasswordRequest),
+    Withdrawn(PathBuf), // - the path of the request's ask file
 }
 
 impl PasswordRequest {
-    pub fn listen() -> Result<impl Stream<Item = PasswordRequest>> {
+    pub fn listen() -> Result<impl Stream<Item = PasswordRequestEvent>> {
         const REQUESTS_DIR: &str = "/run/systemd/ask-password/";
 
         //Configure an inotify listener for the password request directory
         let notify = Inotify::init().context("failed to init inotify")?;
         notify
             .watches()
-            .add(REQUESTS_DIR, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+            .add(
+                REQUESTS_DIR,
+                WatchMask::CLOSE_WRITE
+                    | WatchMask::MOVED_TO
+                    | WatchMask::DELETE
+                    | WatchMask::MOVED_FROM,
+            )
             .context("failed to add inotify watcher")?;
 
         // - wrap the inotify code in an async stream for easier consumption
@@ -43,8 +67,13 @@ impl PasswordRequest {
 
                     let events: Vec<_> = notify
                         .read_events(&mut buffer)?
-                        .filter_map(|ev| ev.name)
-                        .map(|n| Path::new(REQUESTS_DIR).join(n))
+                        .filter_map(|ev| {
+                            let withdrawn = ev
+                                .mask
+                                .intersects(EventMask::DELETE | EventMask::MOVED_FROM);
+                            ev.name
+                                .map(|n| (Path::new(REQUESTS_DIR).join(n), withdrawn))
+                        })
                         .collect();
 
                     if !events.is_empty() {
@@ -63,12 +92,15 @@ impl PasswordRequest {
         // - handle existing requests
         let mut reqs = Vec::new();
         for ent in std::fs::read_dir(REQUESTS_DIR).context("failed to read requests dir")? {
-            reqs.push(ent.context("failed to read request dir entry")?.path());
+            reqs.push((
+                ent.context("failed to read request dir entry")?.path(),
+                false,
+            ));
         }
         let events = smol::stream::iter(reqs).chain(events);
 
         //Handle any events that come in
-        Ok(events.filter_map(|path| {
+        Ok(events.filter_map(|(path, withdrawn)| {
             //We only care about files which start with `ask.XXXXXXX`
             if path
                 .file_name()
@@ -78,6 +110,11 @@ impl PasswordRequest {
                 return None;
             }
 
+            //Check if the request was withdrawn
+            if withdrawn {
+                return Some(PasswordRequestEvent::Withdrawn(path));
+            }
+
             //Check if the file still exists
             if !path.exists() {
                 return None;
@@ -85,7 +122,7 @@ impl PasswordRequest {
 
             //Try to load the request from the INI file
             match Self::load_from_ini(&path) {
-                Ok(req) => Some(req),
+                Ok(req) => Some(PasswordRequestEvent::Added(req)),
                 Err(err) => {
                     eprintln!("failed to load password request from ini file {path:?}: {err:#}");
                     None
@@ -102,13 +139,81 @@ impl PasswordRequest {
         let id = ask_section.get("Id");
         let message = ask_section.get("Message");
 
+        let pid = ask_section
+            .get("PID")
+            .map(|pid| pid.parse().context("malformed PID property"))
+            .transpose()?
+            .filter(|&pid| pid > 0)
+            .map(Pid::from_raw);
+
+        // - a NotAfter value of 0 indicates that the request never expires
+        let not_after = ask_section
+            .get("NotAfter")
+            .map(|t| t.parse().context("malformed NotAfter property"))
+            .transpose()?
+            .filter(|&t| t != 0)
+            .map(Duration::from_micros);
+
+        let parse_bool = |key: &str| {
+            ask_section
+                .get(key)
+                .map(|v| match v {
+                    "1" | "yes" | "true" | "on" => Ok(true),
+                    "0" | "no" | "false" | "off" => Ok(false),
+                    _ => Err(anyhow::anyhow!("malformed {key} property")),
+                })
+                .transpose()
+                .map(Option::unwrap_or_default)
+        };
+
         Ok(PasswordRequest {
+            ask_path: path.to_owned(),
             id: id.map(String::from),
             message: message.map(String::from),
+            pid,
+            not_after,
+            accept_cached: parse_bool("AcceptCached")?,
+            echo: parse_bool("Echo")?,
             socket_path: PathBuf::from(socket),
         })
     }
 
+    pub fn ask_path(&self) -> &Path {
+        &self.ask_path
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let Some(not_after) = self.not_after else {
+            return false;
+        };
+
+        let now = clock_gettime(ClockId::CLOCK_MONOTONIC).expect("failed to read monotonic clock");
+        Duration::from(now) > not_after
+    }
+
+    pub fn is_requester_alive(&self) -> bool {
+        let Some(pid) = self.pid else {
+            return true;
+        };
+
+        is_process_alive(pid)
+    }
+
+    pub fn requester_cmdline(&self) -> Option<Vec<String>> {
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", self.pid?)).ok()?;
+        Some(
+            cmdline
+                .split(|&b| b == 0)
+                .filter(|a| !a.is_empty())
+                .map(|a| String::from_utf8_lossy(a).into_owned())
+                .collect(),
+        )
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.is_expired() || !self.is_requester_alive()
+    }
+
     pub fn reply(self, password: Option<Zeroizing<Box<str>>>) -> Result<()> {
         let socket = UnixDatagram::unbound().context("failed to open client socket")?;
 
@@ -135,3 +240,48 @@ impl PasswordRequest {
         Ok(())
     }
 }
+
+pub fn is_process_alive(pid: Pid) -> bool {
+    //Probe the process using a null signal; EPERM still means that it exists
+    !matches!(kill(pid, None), Err(Errno::ESRCH))
+}
+
+//Mimics the behavior of `systemd-ask-password --keyname=...`, which allows consumers setting `AcceptCached=1` to reuse passwords
+pub fn push_password_to_keyring(keyname: &str, password: &str) -> Result<()> {
+    use linux_keyutils::{KeyRing, KeyRingIdentifier};
+
+    // - this matches the cache timeout used by systemd
+    const KEYRING_TIMEOUT_SECS: usize = 150;
+
+    let keyring = KeyRing::from_special_id(KeyRingIdentifier::User, true)
+        .context("failed to open user keyring")?;
+
+    //Merge the password with any existing cached passwords (which are NUL-separated)
+    let mut passwords = Zeroizing::new(match keyring.search(keyname) {
+        Ok(key) => key
+            .read_to_vec()
+            .context("failed to read cached passwords")?,
+        Err(_) => Vec::new(),
+    });
+
+    if passwords
+        .split(|&b| b == 0)
+        .any(|p| p == password.as_bytes())
+    {
+        return Ok(());
+    }
+
+    if !passwords.is_empty() {
+        passwords.push(0);
+    }
+    passwords.extend_from_slice(password.as_bytes());
+
+    let key = keyring
+        .add_key(keyname, &*passwords)
+        .context("failed to add cached passwords to keyring")?;
+
+    key.set_timeout(KEYRING_TIMEOUT_SECS)
+        .context("failed to set cached passwords key timeout")?;
+
+    Ok(())
+}
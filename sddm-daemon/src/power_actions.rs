@@ -117,6 +117,23 @@ impl PowerActionClient {
             eprintln!("failed to perform power action {act:?}: {err:#}")
         }
     }
+
+    // - this isn't really a power action, but we already have a connection to the manager anyway
+    pub async fn stop_unit(&self, unit: &str) -> Result<()> {
+        let mut dbus_client = self.dbus_client.lock().await;
+        dbus_client
+            .call::<(&str, &str), (zvariant::OwnedObjectPath,)>(
+                "org.freedesktop.systemd1",
+                "/org/freedesktop/systemd1",
+                "org.freedesktop.systemd1.Manager",
+                "StopUnit",
+                &(unit, "replace"),
+            )
+            .await
+            .with_context(|| format!("failed to stop systemd unit {unit:?}"))?;
+
+        Ok(())
+    }
 }
 
 //The manager D-Bus impl isn't spec compliant, so we have to slightly reinvent the wheel here
@@ -1,13 +1,37 @@
-use std::path::{Path, PathBuf};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
 
-use crate::login_controller::LoginRequest;
+use crate::{
+    device_spec::DeviceSpec, greeter_protocol::SessionType, login_controller::LoginRequest,
+};
 
 pub struct SddmConfig {
     pub greeter: PathBuf,
     pub theme: Option<PathBuf>,
-    pub luks_devices: Vec<PathBuf>,
+    pub greeter_restarts: u32, // - how often the greeter is restarted after crashing before giving up
+    pub x11_session_dirs: Vec<PathBuf>,
+    pub wayland_session_dirs: Vec<PathBuf>,
+    pub allowed_users: Vec<String>, // - if empty, any user may log in
+    pub luks_devices: Vec<DeviceSpec>,
+    pub separate_passphrase_devices: Vec<DeviceSpec>, // - devices which don't use the login password
+    pub user_keyslot_devices: Vec<DeviceSpec>, // - devices which may only be unlocked using keyslots bound to the user logging in
+    pub cache_passphrase: bool,
+    //Check passphrases against the LUKS2 header before answering cryptsetup
+    // - this runs the KDF of each keyslot we try, on top of cryptsetup running it again to unlock the device;
+    //   expect logins to take about twice as long, or longer if the passphrase is only accepted by a later keyslot
+    pub verify_passphrase: bool,
+    pub unlock_tries: Option<u32>, // - 0 means unlimited; if not set, the crypttab tries= option is used
+    pub device_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>, // - power off after not receiving any input for this long
+    pub failure_delay: Option<Duration>, // - doubled after each consecutive failed login, up to `max_failure_delay`
+    pub max_failure_delay: Duration,
+    pub power_off_after_failures: Option<u32>,
+    pub efivarfs_root: PathBuf, // - where the persistent failed unlock counter is stored
 }
 
 impl SddmConfig {
@@ -31,6 +55,22 @@ impl SddmConfig {
             None
         };
 
+        // - SDDM accepts comma-separated lists of session directories
+        let session_dirs = |section: &str| -> Vec<PathBuf> {
+            ini.get_from(Some(section), "SessionDir")
+                .map(|dirs| {
+                    dirs.split(',')
+                        .map(str::trim)
+                        .filter(|d| !d.is_empty())
+                        .map(PathBuf::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let x11_session_dirs = session_dirs("X11");
+        let wayland_session_dirs = session_dirs("Wayland");
+
         let luks_unlock = ini
             .section(Some("LUKSUnlock"))
             .context("no LUKSUnlock section")?;
@@ -40,14 +80,126 @@ impl SddmConfig {
             .context("no Greeter config value")?;
         let greeter = PathBuf::from(greeter);
 
-        let luks_devices = luks_unlock.get_all("Devices").map(PathBuf::from).collect();
+        let greeter_restarts = luks_unlock
+            .get("GreeterRestarts")
+            .map(|v| v.parse().context("malformed GreeterRestarts config value"))
+            .transpose()?
+            .unwrap_or(3);
+
+        let allowed_users = luks_unlock.get_all("Users").map(String::from).collect();
+
+        let luks_devices = luks_unlock
+            .get_all("Devices")
+            .map(DeviceSpec::parse)
+            .collect();
+
+        let separate_passphrase_devices = luks_unlock
+            .get_all("SeparatePassphraseDevices")
+            .map(DeviceSpec::parse)
+            .collect();
+
+        let user_keyslot_devices = luks_unlock
+            .get_all("UserKeyslotDevices")
+            .map(DeviceSpec::parse)
+            .collect();
+
+        let cache_passphrase = luks_unlock
+            .get("CachePassphrase")
+            .map(|v| v.parse().context("malformed CachePassphrase config value"))
+            .transpose()?
+            .unwrap_or(false);
+
+        let verify_passphrase = luks_unlock
+            .get("VerifyPassphrase")
+            .map(|v| v.parse().context("malformed VerifyPassphrase config value"))
+            .transpose()?
+            .unwrap_or(false);
+
+        let unlock_tries = luks_unlock
+            .get("Tries")
+            .map(|v| v.parse().context("malformed Tries config value"))
+            .transpose()?;
+
+        // - a timeout of 0 disables the missing device watchdog
+        let device_timeout = luks_unlock
+            .get("DeviceTimeout")
+            .map(|v| v.parse().context("malformed DeviceTimeout config value"))
+            .transpose()?
+            .map_or(Some(30), |t: u64| (t != 0).then_some(t))
+            .map(Duration::from_secs);
+
+        let idle_timeout = luks_unlock
+            .get("IdleTimeout")
+            .map(|v| v.parse().context("malformed IdleTimeout config value"))
+            .transpose()?
+            .filter(|&t: &u64| t != 0)
+            .map(Duration::from_secs);
+
+        // - a delay of 0 disables throttling failed logins
+        let failure_delay = luks_unlock
+            .get("FailureDelay")
+            .map(|v| v.parse().context("malformed FailureDelay config value"))
+            .transpose()?
+            .map_or(Some(2), |t: u64| (t != 0).then_some(t))
+            .map(Duration::from_secs);
+
+        let max_failure_delay = luks_unlock
+            .get("MaxFailureDelay")
+            .map(|v| v.parse().context("malformed MaxFailureDelay config value"))
+            .transpose()?
+            .map_or(Duration::from_secs(300), Duration::from_secs);
+
+        let power_off_after_failures = luks_unlock
+            .get("PowerOffAfterFailures")
+            .map(|v| {
+                v.parse()
+                    .context("malformed PowerOffAfterFailures config value")
+            })
+            .transpose()?
+            .filter(|&n: &u32| n != 0);
+
+        let efivarfs_root = PathBuf::from(
+            luks_unlock
+                .get("EfivarfsRoot")
+                .unwrap_or("/sys/firmware/efi/efivars"),
+        );
 
         Ok(SddmConfig {
             greeter,
             theme,
+            greeter_restarts,
+            x11_session_dirs,
+            wayland_session_dirs,
+            allowed_users,
             luks_devices,
+            separate_passphrase_devices,
+            user_keyslot_devices,
+            cache_passphrase,
+            verify_passphrase,
+            unlock_tries,
+            device_timeout,
+            idle_timeout,
+            failure_delay,
+            max_failure_delay,
+            power_off_after_failures,
+            efivarfs_root,
         })
     }
+
+    //Checks whether a session with the given file name exists in the session directories of the given type
+    // - greeters which don't report a session type get their session looked up in all session directories
+    pub fn has_session(&self, name: &OsStr, session_type: SessionType) -> bool {
+        let dirs = match session_type {
+            SessionType::X11 => &self.x11_session_dirs[..],
+            SessionType::Wayland => &self.wayland_session_dirs[..],
+            SessionType::Unknown => {
+                return self.has_session(name, SessionType::Wayland)
+                    || self.has_session(name, SessionType::X11);
+            }
+        };
+
+        dirs.iter().any(|dir| dir.join(name).is_file())
+    }
 }
 
 pub fn write_transient_sddm_config(request: &LoginRequest) -> Result<()> {
@@ -84,12 +236,19 @@ pub fn write_transient_sddm_config(request: &LoginRequest) -> Result<()> {
         .expect("failed to link password key into root keyring");
 
     //Write the config file
+    // - SDDM's autologin has no way to specify the session type; it looks up the session file name in the Wayland session directories first, then the X11 ones
+    //   logins for X11 sessions shadowed by a Wayland session of the same name are never handed off, so the file name always resolves to the chosen session type
     let session = request
         .session
         .file_name()
         .and_then(|s| s.to_str())
         .context("malformed login session")?;
 
+    println!(
+        "handing off {:?} session {session} for user {:?}",
+        request.session_type, request.user
+    );
+
     let mut file = std::fs::File::create_new(file)?;
     writeln!(file, "[Autologin]")?;
     writeln!(file, "User={}", request.user)?;
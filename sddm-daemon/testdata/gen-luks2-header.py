@@ -0,0 +1,133 @@
+#!/usr/bin/env python3
+#Generates the LUKS2 header fixture used by the tests in src/luks2.rs
+# - this deliberately doesn't share any code with the Rust implementation, so that it can serve as a known-answer test
+# - all random values are derived from a fixed seed, so the output is reproducible
+#
+# The fixture mirrors what the following cryptsetup invocations produce (truncated after the last keyslot area):
+#   cryptsetup luksFormat --type luks2 --key-size 256 --pbkdf pbkdf2 --pbkdf-force-iterations 1000 luks2-header.img <<< "correct horse"
+#   cryptsetup luksAddKey --pbkdf argon2id --pbkdf-force-iterations 4 --pbkdf-memory 64 --pbkdf-parallel 1 luks2-header.img
+#   cryptsetup token import luks2-header.img <<< '{"type": "luks-stage1-sddm-user", "keyslots": ["1"], "user": "alice"}'
+
+import base64
+import hashlib
+import json
+import struct
+import sys
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.argon2 import Argon2id
+
+KEY_SIZE = 32  # - aes-xts-plain64 with a 256 bit key
+STRIPES = 4000
+SECTOR_SIZE = 512
+HDR_SIZE = 16384
+KEYSLOTS_OFFSET = 2 * HDR_SIZE
+KEYSLOT_AREA_SIZE = 131072
+
+PASSPHRASES = [b"correct horse", b"battery staple"]
+
+_rng_counter = 0
+
+
+def random_bytes(n):
+    global _rng_counter
+    out = b""
+    while len(out) < n:
+        out += hashlib.sha256(b"luks-stage1-sddm fixture" + struct.pack(">Q", _rng_counter)).digest()
+        _rng_counter += 1
+    return out[:n]
+
+
+def xor(a, b):
+    return bytes(x ^ y for x, y in zip(a, b))
+
+
+def diffuse(data):
+    out = b""
+    for i in range(0, len(data), 32):
+        chunk = data[i : i + 32]
+        out += hashlib.sha256(struct.pack(">I", i // 32) + chunk).digest()[: len(chunk)]
+    return out
+
+
+def af_split(key):
+    stripes = [random_bytes(len(key)) for _ in range(STRIPES - 1)]
+    d = bytes(len(key))
+    for s in stripes:
+        d = diffuse(xor(d, s))
+    stripes.append(xor(d, key))
+    return b"".join(stripes)
+
+
+def xts_encrypt(key, data):
+    data += bytes(-len(data) % SECTOR_SIZE)
+    out = b""
+    for sector in range(len(data) // SECTOR_SIZE):
+        enc = Cipher(algorithms.AES(key), modes.XTS(struct.pack("<Q", sector) + bytes(8))).encryptor()
+        out += enc.update(data[sector * SECTOR_SIZE : (sector + 1) * SECTOR_SIZE]) + enc.finalize()
+    return out
+
+
+def b64(data):
+    return base64.b64encode(data).decode()
+
+
+volume_key = random_bytes(KEY_SIZE)
+
+keyslots = {}
+areas = []
+for idx, passphrase in enumerate(PASSPHRASES):
+    salt = random_bytes(32)
+    if idx == 0:
+        kdf = {"type": "pbkdf2", "hash": "sha256", "iterations": 1000, "salt": b64(salt)}
+        area_key = hashlib.pbkdf2_hmac("sha256", passphrase, salt, 1000, KEY_SIZE)
+    else:
+        kdf = {"type": "argon2id", "time": 4, "memory": 64, "cpus": 1, "salt": b64(salt)}
+        area_key = Argon2id(salt=salt, length=KEY_SIZE, iterations=4, lanes=1, memory_cost=64).derive(passphrase)
+
+    area = xts_encrypt(area_key, af_split(volume_key))
+    assert len(area) <= KEYSLOT_AREA_SIZE
+    offset = KEYSLOTS_OFFSET + idx * KEYSLOT_AREA_SIZE
+
+    keyslots[str(idx)] = {
+        "type": "luks2",
+        "key_size": KEY_SIZE,
+        "af": {"type": "luks1", "stripes": STRIPES, "hash": "sha256"},
+        "area": {
+            "type": "raw",
+            "offset": str(offset),
+            "size": str(KEYSLOT_AREA_SIZE),
+            "encryption": "aes-xts-plain64",
+            "key_size": KEY_SIZE,
+        },
+        "kdf": kdf,
+    }
+    areas.append((offset, area))
+
+digest_salt = random_bytes(32)
+metadata = {
+    "keyslots": keyslots,
+    "tokens": {"0": {"type": "luks-stage1-sddm-user", "keyslots": ["1"], "user": "alice"}},
+    "segments": {},
+    "digests": {
+        "0": {
+            "type": "pbkdf2",
+            "keyslots": list(keyslots),
+            "segments": [],
+            "hash": "sha256",
+            "iterations": 1000,
+            "salt": b64(digest_salt),
+            "digest": b64(hashlib.pbkdf2_hmac("sha256", volume_key, digest_salt, 1000, 32)),
+        }
+    },
+    "config": {"json_size": str(HDR_SIZE - 4096), "keyslots_size": str(len(keyslots) * KEYSLOT_AREA_SIZE)},
+}
+
+img = bytearray(KEYSLOTS_OFFSET + len(keyslots) * KEYSLOT_AREA_SIZE)
+img[0:16] = b"LUKS\xba\xbe" + struct.pack(">HQ", 2, HDR_SIZE)
+json_area = json.dumps(metadata).encode()
+img[4096 : 4096 + len(json_area)] = json_area
+for offset, area in areas:
+    img[offset : offset + len(area)] = area
+
+sys.stdout.buffer.write(img)